@@ -13,7 +13,7 @@ pub struct TeraEmbed<R: RustEmbed> {
 
 impl <R: RustEmbed> TeraEmbed<R> {
     pub fn new() -> Self {
-        Self { _embed: PhantomData::default() }
+        Self { _embed: PhantomData }
     }
 
     pub fn tera(&self) -> tera::Result<Arc<Tera>> {
@@ -32,8 +32,14 @@ impl <R: RustEmbed> TeraEmbed<R> {
     }
 }
 
+impl <R: RustEmbed> Default for TeraEmbed<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl <R: RustEmbed> Clone for TeraEmbed<R> {
     fn clone(&self) -> Self {
-        Self { _embed: self._embed.clone() }
+        Self { _embed: self._embed }
     }
 }
\ No newline at end of file
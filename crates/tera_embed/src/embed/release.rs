@@ -20,7 +20,7 @@ impl <R: RustEmbed> TeraEmbed<R> {
 
         Self { 
             arc_tera,
-            _embed: PhantomData::default(),
+            _embed: PhantomData,
         }
     }
 
@@ -44,12 +44,18 @@ impl <R: RustEmbed> TeraEmbed<R> {
     }
 }
 
+impl <R: RustEmbed> Default for TeraEmbed<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Can't derive clone because R: doesn't implement Clone. 
 // And there's no reason for that restriction.
 impl <R: RustEmbed> Clone for TeraEmbed<R> {
     fn clone(&self) -> Self {
         Self { 
-            _embed: self._embed.clone(),
+            _embed: self._embed,
             arc_tera: self.arc_tera.clone(),
         }
     }
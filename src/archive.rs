@@ -0,0 +1,42 @@
+//! When entries were written, by year, month and day.
+//!
+//! Entry timestamps aren't encrypted, so none of this needs the private key.
+
+#[cfg(test)]
+mod tests;
+
+use chrono::{Datelike, NaiveDate};
+
+/// The weeks of a month, starting on Monday. Days outside of the month are None.
+/// Returns None if there's no such month.
+pub(crate) fn month_weeks(year: i32, month: u32) -> Option<Vec<[Option<NaiveDate>; 7]>> {
+    let mut day = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let mut weeks = Vec::new();
+    let mut week = [None; 7];
+    while day.month() == month {
+        let weekday = day.weekday().num_days_from_monday() as usize;
+        week[weekday] = Some(day);
+        if weekday == 6 {
+            weeks.push(week);
+            week = [None; 7];
+        }
+        day = match day.succ_opt() {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    if week.iter().any(Option::is_some) {
+        weeks.push(week);
+    }
+    Some(weeks)
+}
+
+/// The (year, month) before this one.
+pub(crate) fn previous_month(year: i32, month: u32) -> (i32, u32) {
+    if month == 1 { (year - 1, 12) } else { (year, month - 1) }
+}
+
+/// The (year, month) after this one.
+pub(crate) fn next_month(year: i32, month: u32) -> (i32, u32) {
+    if month == 12 { (year + 1, 1) } else { (year, month + 1) }
+}
@@ -0,0 +1,25 @@
+use chrono::Datelike;
+
+use super::{month_weeks, next_month, previous_month};
+
+#[test]
+fn test_month_weeks() {
+    // October 2026 starts on a Thursday and ends on a Saturday.
+    let weeks = month_weeks(2026, 10).unwrap();
+    assert_eq!(weeks.len(), 5);
+
+    let days = |week: &[Option<chrono::NaiveDate>; 7]| -> Vec<u32> {
+        week.iter().map(|d| d.map(|d| d.day()).unwrap_or(0)).collect()
+    };
+    assert_eq!(days(&weeks[0]), vec![0, 0, 0, 1, 2, 3, 4]);
+    assert_eq!(days(&weeks[4]), vec![26, 27, 28, 29, 30, 31, 0]);
+
+    assert!(month_weeks(2026, 13).is_none());
+}
+
+#[test]
+fn test_adjacent_months() {
+    assert_eq!(previous_month(2026, 1), (2025, 12));
+    assert_eq!(previous_month(2026, 10), (2026, 9));
+    assert_eq!(next_month(2026, 12), (2027, 1));
+}
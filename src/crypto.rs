@@ -3,7 +3,10 @@ mod tests;
 
 use std::fmt::Display;
 
-use sodiumoxide::crypto::{sealedbox, secretbox, box_};
+use sodiumoxide::crypto::{sealedbox, secretbox, box_, generichash, pwhash::argon2id13, secretstream::xchacha20poly1305 as secretstream};
+
+/// The `context` for SealedBoxPrivateKey::keyed_hash() of API tokens.
+const API_TOKEN_CONTEXT: &str = "vault.api";
 
 #[derive(Clone)]
 pub(crate) struct SecretBox {
@@ -17,6 +20,30 @@ impl SecretBox {
         }
     }
 
+    /// Derive a key from a passphrase with Argon2id. This is slow on purpose.
+    pub(crate) fn from_passphrase(passphrase: &str, salt: &argon2id13::Salt) -> anyhow::Result<Self> {
+        let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+        argon2id13::derive_key(
+            &mut key.0,
+            passphrase.as_bytes(),
+            salt,
+            argon2id13::OPSLIMIT_INTERACTIVE,
+            argon2id13::MEMLIMIT_INTERACTIVE,
+        ).map_err(|_| anyhow::format_err!("Error deriving key from passphrase"))?;
+        Ok(Self{key})
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let key = secretbox::Key::from_slice(bytes).ok_or_else(
+            || anyhow::format_err!("Expected {} bytes for a secret box key", secretbox::KEYBYTES)
+        )?;
+        Ok(Self{key})
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.key.0
+    }
+
     pub(crate) fn encrypt(&self, data: &[u8]) -> Vec<u8> {
         let mut out = Vec::with_capacity(secretbox::NONCEBYTES + data.len());
         let nonce = secretbox::gen_nonce();
@@ -39,6 +66,139 @@ impl SecretBox {
     }
 }
 
+/// Hash a password for storing in settings, with Argon2id. This is slow on purpose.
+pub(crate) fn hash_password(password: &str) -> anyhow::Result<String> {
+    let hash = argon2id13::pwhash(
+        password.as_bytes(),
+        argon2id13::OPSLIMIT_INTERACTIVE,
+        argon2id13::MEMLIMIT_INTERACTIVE,
+    ).map_err(|_| anyhow::format_err!("Error hashing password"))?;
+    // An ASCII string, padded with NULs.
+    Ok(std::str::from_utf8(&hash.0)?.trim_end_matches('\0').to_string())
+}
+
+pub(crate) fn verify_password(hash: &str, password: &str) -> bool {
+    let mut padded = hash.as_bytes().to_vec();
+    if padded.len() >= argon2id13::HASHEDPASSWORDBYTES {
+        return false;
+    }
+    padded.resize(argon2id13::HASHEDPASSWORDBYTES, 0);
+    match argon2id13::HashedPassword::from_slice(&padded) {
+        Some(hash) => argon2id13::pwhash_verify(&hash, password.as_bytes()),
+        None => false,
+    }
+}
+
+/// Run hash_password(), verify_password(), or SealedBoxPrivateKey::from_wrapped() from async code.
+/// Argon2 is slow on purpose, so keep it off of the async executor threads.
+pub(crate) async fn argon2<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    async_std::task::spawn_blocking(f).await
+}
+
+/// Encrypts files, which can be too big to comfortably seal in one box.
+/// Each file gets its own key, which is then sealed like entry contents.
+pub(crate) struct StreamKey {
+    key: secretstream::Key,
+}
+
+impl StreamKey {
+    /// Plaintext bytes per encrypted chunk.
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    pub(crate) fn generate() -> Self {
+        Self {
+            key: secretstream::gen_key()
+        }
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let key = secretstream::Key::from_slice(bytes).ok_or_else(
+            || anyhow::format_err!("Expected {} bytes for a stream key", secretstream::KEYBYTES)
+        )?;
+        Ok(Self{key})
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+
+    /// The stream header, followed by each encrypted chunk. The last chunk is tagged final,
+    /// so that a truncated file fails to decrypt.
+    #[cfg(test)]
+    pub(crate) fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let mut encryptor = self.encryptor();
+        encryptor.push(data);
+        encryptor.finish()
+    }
+
+    /// Encrypt a file a piece at a time, as it arrives. Gives the same output as encrypt().
+    pub(crate) fn encryptor(&self) -> StreamEncryptor {
+        let (stream, header) = secretstream::Stream::init_push(&self.key)
+            .expect("Initializing a secretstream shouldn't fail");
+        StreamEncryptor {
+            stream,
+            pending: Vec::with_capacity(Self::CHUNK_SIZE),
+            out: header.as_ref().to_vec(),
+        }
+    }
+
+    pub(crate) fn decrypt(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if secretstream::HEADERBYTES > data.len() {
+            return Err(anyhow::format_err!("Expected at least {} bytes for the header", secretstream::HEADERBYTES));
+        }
+        let (header_bytes, cypher) = data.split_at(secretstream::HEADERBYTES);
+        let header = secretstream::Header::from_slice(header_bytes).expect("We specified the right header size.");
+        let mut stream = secretstream::Stream::init_pull(&header, &self.key)
+            .map_err(|_| anyhow::format_err!("Error decrypting."))?;
+
+        let mut out = Vec::with_capacity(cypher.len());
+        for chunk in cypher.chunks(Self::CHUNK_SIZE + secretstream::ABYTES) {
+            if stream.is_finalized() {
+                return Err(anyhow::format_err!("Unexpected data after the final chunk"));
+            }
+            let (plain, _tag) = stream.pull(chunk, None).map_err(
+                |_| anyhow::format_err!("Error decrypting.")
+            )?;
+            out.extend(plain);
+        }
+        if stream.is_not_finalized() {
+            return Err(anyhow::format_err!("Encrypted data is truncated"));
+        }
+        Ok(out)
+    }
+}
+
+/// See StreamKey::encryptor().
+pub(crate) struct StreamEncryptor {
+    stream: secretstream::Stream<secretstream::Push>,
+    /// Plaintext not yet encrypted. Always holds the last chunk, since we can't tag it final
+    /// until we know no more is coming.
+    pending: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl StreamEncryptor {
+    pub(crate) fn push(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.pending.len() == StreamKey::CHUNK_SIZE {
+                self.out.extend(self.stream.push(&self.pending, None, secretstream::Tag::Message)
+                    .expect("Pushing to a secretstream shouldn't fail"));
+                self.pending.clear();
+            }
+            let take = data.len().min(StreamKey::CHUNK_SIZE - self.pending.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+        }
+    }
+
+    /// The encrypted file. An empty file is still one (empty) final chunk.
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        self.out.extend(self.stream.push(&self.pending, None, secretstream::Tag::Final)
+            .expect("Pushing to a secretstream shouldn't fail"));
+        self.out
+    }
+}
+
 #[derive(Clone, PartialEq)]
 pub(crate) struct SealedBoxPublicKey {
     key: box_::PublicKey,
@@ -113,6 +273,54 @@ impl SealedBoxPrivateKey {
         })
     }
 
+    /// Encrypt this key with a passphrase.
+    /// Returns base58(salt + secretbox), for storing in the DB settings.
+    pub fn wrap(&self, passphrase: &str) -> anyhow::Result<String> {
+        let salt = argon2id13::gen_salt();
+        let secret_box = SecretBox::from_passphrase(passphrase, &salt)?;
+
+        let mut out = Vec::new();
+        out.extend_from_slice(salt.as_ref());
+        out.extend_from_slice(&secret_box.encrypt(self.bytes()));
+        Ok(bs58::encode(out).into_string())
+    }
+
+    /// Decrypt a key that was encrypted with wrap().
+    pub fn from_wrapped(wrapped: &str, passphrase: &str) -> anyhow::Result<Self> {
+        let bytes = bs58::decode(wrapped).into_vec()?;
+        if argon2id13::SALTBYTES > bytes.len() {
+            return Err(anyhow::format_err!("Expected at least {} bytes for the salt", argon2id13::SALTBYTES));
+        }
+        let (salt_bytes, cypher) = bytes.split_at(argon2id13::SALTBYTES);
+        let salt = argon2id13::Salt::from_slice(salt_bytes).expect("We specified the right salt size.");
+
+        let secret_box = SecretBox::from_passphrase(passphrase, &salt)?;
+        let key_bytes = secret_box.decrypt(cypher).map_err(|_| anyhow::format_err!("Incorrect passphrase"))?;
+        Self::from_bytes(&key_bytes)
+    }
+
+    /// A bearer token for the JSON API. Always the same for a given private key and `salt`, so
+    /// each time the token is enabled it should get a new salt, or a revoked token would come back.
+    /// It only unlocks the vault if the private key has been wrapped with it. See wrap_with_token().
+    pub fn api_token(&self, salt: &[u8]) -> String {
+        bs58::encode(self.keyed_hash(API_TOKEN_CONTEXT, salt)).into_string()
+    }
+
+    /// Like wrap(), but with an api_token() instead of a passphrase. Tokens are random enough
+    /// to use as a key directly, so this is fast enough to check on every request.
+    pub fn wrap_with_token(&self, token: &str) -> anyhow::Result<String> {
+        let secret_box = SecretBox::from_bytes(&bs58::decode(token).into_vec()?)?;
+        Ok(bs58::encode(secret_box.encrypt(self.bytes())).into_string())
+    }
+
+    /// Decrypt a key that was encrypted with wrap_with_token().
+    pub fn from_token_wrapped(wrapped: &str, token: &str) -> anyhow::Result<Self> {
+        let secret_box = SecretBox::from_bytes(&bs58::decode(token).into_vec()?)?;
+        let key_bytes = secret_box.decrypt(&bs58::decode(wrapped).into_vec()?)
+            .map_err(|_| anyhow::format_err!("Incorrect token"))?;
+        Self::from_bytes(&key_bytes)
+    }
+
     pub fn public(&self) -> &SealedBoxPublicKey { &self.public_key }
 
     pub fn decrypt(&self, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
@@ -126,8 +334,19 @@ impl SealedBoxPrivateKey {
         Ok(String::from_utf8(decrypted)?)
     }
 
+    /// A hash of `data` that can only be computed (or checked) with this private key.
+    /// `context` keeps hashes for different purposes distinct.
+    pub fn keyed_hash(&self, context: &str, data: &[u8]) -> Vec<u8> {
+        // Don't use the private key directly as a MAC key. Derive one for this context:
+        let subkey = generichash::hash(context.as_bytes(), None, Some(self.bytes()))
+            .expect("Private key is a valid generichash key size");
+        let digest = generichash::hash(data, None, Some(subkey.as_ref()))
+            .expect("Digest is a valid generichash key size");
+        digest.as_ref().to_vec()
+    }
+
     pub fn bytes(&self) -> &[u8] {
-        return &self.private_key.as_ref();
+        self.private_key.as_ref()
     }
 
 }
@@ -1,4 +1,16 @@
-use super::SealedBoxPrivateKey;
+use std::process::Command;
+
+use sodiumoxide::base64::{self, Variant};
+
+use super::{SealedBoxPrivateKey, StreamKey, hash_password, verify_password};
+
+/// A sealed box from static/sealedbox.js, which `vault serve --client-side` uses instead of libsodium.
+/// Made with `sealedBox.sealString("Sealed in JavaScript ✓", sealedBox.publicKey(JS_PRIVATE_KEY))`.
+const JS_PRIVATE_KEY: &str = "XLHYcP549cW4N2uZq6rU17H5eh2Kh6CWTijQ9Kic52K";
+const JS_SEALED: &str = "1yciw6Iji3HeqOZaYQ5et1m8GPE8ekFK6i9Pf5kqwTbAgBiRc9mCg+7dhRB96J9xQMCT47hLSag6V6GJM9+Qd18jikNfum1+";
+/// Long enough to need three blocks of XSalsa20 keystream. Sealed the same way, with JS_PRIVATE_KEY.
+const JS_LONG_MESSAGE: &str = "Sealed in JavaScript, long enough that XSalsa20 needs more than one 64-byte block of keystream for it: ✓✓✓✓✓✓✓✓";
+const JS_SEALED_LONG: &str = "ndtqIYpm6RlsiicFXwxlns5KuEdS7StUVEn6BwixOhYXvLBT+fkImGlgJgMu7OGE2ihPoqdjudqSNqPhSwZRxw6psN+sVaqTKek5Z1dFvgh1N85LilGF7G7VRYYgwmS5N+qN6DuZ3y35BGBp01umc44+Axy6xri59Zdw9LZjw/2YN+8Y3kfMufB9O2/9fnxgKUmvJs/8GuHA//hHHP43YSPDckz5l805Cgq6pyORpQ==";
 
 #[test]
 fn test_derive() {
@@ -8,4 +20,149 @@ fn test_derive() {
     let secret2 = SealedBoxPrivateKey::from_base58(&secret_str).unwrap();
 
     assert_eq!(secret.public().to_string(), secret2.public().to_string());
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_wrap() {
+    let secret = SealedBoxPrivateKey::generate();
+    let wrapped = secret.wrap("correct horse battery staple").unwrap();
+
+    let secret2 = SealedBoxPrivateKey::from_wrapped(&wrapped, "correct horse battery staple").unwrap();
+    assert_eq!(secret.to_string(), secret2.to_string());
+
+    assert!(SealedBoxPrivateKey::from_wrapped(&wrapped, "incorrect horse battery staple").is_err());
+}
+
+#[test]
+fn test_keyed_hash() {
+    let secret = SealedBoxPrivateKey::generate();
+    let other = SealedBoxPrivateKey::generate();
+
+    let hash = secret.keyed_hash("tag", b"work");
+    assert_eq!(hash, secret.keyed_hash("tag", b"work"));
+    assert_ne!(hash, secret.keyed_hash("tag", b"play"));
+    assert_ne!(hash, secret.keyed_hash("other", b"work"));
+    assert_ne!(hash, other.keyed_hash("tag", b"work"));
+}
+
+#[test]
+fn test_stream_key() {
+    let key = StreamKey::generate();
+    for size in [0, 1, StreamKey::CHUNK_SIZE, StreamKey::CHUNK_SIZE * 2 + 7] {
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let encrypted = key.encrypt(&data);
+        assert_eq!(key.decrypt(&encrypted).unwrap(), data);
+
+        let key2 = StreamKey::from_bytes(key.bytes()).unwrap();
+        assert_eq!(key2.decrypt(&encrypted).unwrap(), data);
+
+        // Dropping the final chunk must not look like a shorter file:
+        if size > StreamKey::CHUNK_SIZE {
+            let truncated = &encrypted[..encrypted.len() - 7 - 17];
+            assert!(key.decrypt(truncated).is_err());
+        }
+    }
+
+    assert!(StreamKey::generate().decrypt(&key.encrypt(b"nope")).is_err());
+}
+
+#[test]
+fn test_stream_encryptor() {
+    let key = StreamKey::generate();
+    for size in [0, 1, StreamKey::CHUNK_SIZE, StreamKey::CHUNK_SIZE * 2 + 7] {
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        // Pieces that don't line up with chunks, like a request body:
+        let mut encryptor = key.encryptor();
+        for piece in data.chunks(1000) {
+            encryptor.push(piece);
+        }
+        let encrypted = encryptor.finish();
+        assert_eq!(encrypted.len(), key.encrypt(&data).len());
+        assert_eq!(key.decrypt(&encrypted).unwrap(), data);
+    }
+}
+
+#[test]
+fn test_api_token() {
+    let secret = SealedBoxPrivateKey::generate();
+    let token = secret.api_token(b"salt");
+    assert_eq!(token, secret.api_token(b"salt"));
+    assert_ne!(token, secret.api_token(b"pepper"));
+    assert_ne!(token, SealedBoxPrivateKey::generate().api_token(b"salt"));
+
+    let wrapped = secret.wrap_with_token(&token).unwrap();
+    let secret2 = SealedBoxPrivateKey::from_token_wrapped(&wrapped, &token).unwrap();
+    assert_eq!(secret.to_string(), secret2.to_string());
+
+    let other = secret.api_token(b"pepper");
+    assert!(SealedBoxPrivateKey::from_token_wrapped(&wrapped, &other).is_err());
+    assert!(SealedBoxPrivateKey::from_token_wrapped(&wrapped, "not a token").is_err());
+}
+
+#[test]
+fn test_password_hash() {
+    let hash = hash_password("let me write").unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    assert!(verify_password(&hash, "let me write"));
+    assert!(!verify_password(&hash, "let me read"));
+    assert!(!verify_password("garbage", "let me write"));
+}
+
+#[test]
+fn test_open_javascript_sealed_box() {
+    let key = SealedBoxPrivateKey::from_base58(JS_PRIVATE_KEY).unwrap();
+    let sealed = base64::decode(JS_SEALED, Variant::Original).unwrap();
+    assert_eq!(key.decrypt_string(&sealed).unwrap(), "Sealed in JavaScript ✓");
+    let sealed = base64::decode(JS_SEALED_LONG, Variant::Original).unwrap();
+    assert_eq!(key.decrypt_string(&sealed).unwrap(), JS_LONG_MESSAGE);
+}
+
+/// Run `script` after static/sealedbox.js in Node, and return what it prints.
+/// None if Node isn't installed.
+fn run_sealedbox_js(script: &str) -> Option<String> {
+    let source = format!("{}\n{}", include_str!("../../static/sealedbox.js"), script);
+    let output = Command::new("node").arg("-e").arg(source).output().ok()?;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    Some(String::from_utf8(output.stdout).unwrap().trim().to_string())
+}
+
+/// Like test_open_javascript_sealed_box(), but with the current sealedbox.js, in both directions.
+/// The keystream starts 32 bytes into XSalsa20's first 64-byte block, so these lengths end on
+/// either side of block boundaries.
+#[test]
+fn test_sealedbox_js() {
+    const LENGTHS: [usize; 8] = [0, 1, 31, 32, 33, 64, 65, 5000];
+    let message = |len: usize| (0..len).map(|i| (b'a' + (i % 26) as u8) as char).collect::<String>();
+
+    let key = SealedBoxPrivateKey::generate();
+    let from_rust: Vec<String> = LENGTHS.iter()
+        .map(|&len| format!("{:?}", base64::encode(key.public().encrypt(message(len).as_bytes()), Variant::Original)))
+        .collect();
+    let script = format!(r#"
+        const privateKey = sealedBox.base58.decode("{key}")
+        const publicKey = sealedBox.publicKey(privateKey)
+        const message = (len) => Array.from({{length: len}}, (_, i) => String.fromCharCode(97 + i % 26)).join("")
+        console.log(sealedBox.base58.encode(publicKey))
+        const fromRust = [{from_rust}]
+        const lengths = {lengths:?}
+        lengths.forEach((len, i) => {{
+            console.log(sealedBox.openString(fromRust[i], privateKey) === message(len))
+            console.log(sealedBox.sealString(message(len), publicKey))
+        }})
+    "#, key = key, from_rust = from_rust.join(", "), lengths = LENGTHS);
+    let output = match run_sealedbox_js(&script) {
+        Some(output) => output,
+        None => {
+            eprintln!("Skipping: node isn't installed");
+            return;
+        },
+    };
+
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines[0], key.public().to_string());
+    for (i, &len) in LENGTHS.iter().enumerate() {
+        assert_eq!(lines[1 + 2 * i], "true", "JavaScript opening {} bytes", len);
+        let sealed = base64::decode(lines[2 + 2 * i], Variant::Original).unwrap();
+        assert_eq!(key.decrypt_string(&sealed).unwrap(), message(len), "Rust opening {} bytes", len);
+    }
+}
@@ -1,15 +1,163 @@
-use std::path::Path;
+#[cfg(test)]
+mod tests;
+
+use std::{collections::HashMap, path::{Path, PathBuf}};
 
 use anyhow::{Context, bail};
 use async_trait::async_trait;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, TimeZone};
 use sqlx::{FromRow, SqlitePool, query_as, sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions}};
 
-use crate::{crypto, server::{ReadQuery}};
+use crate::{crypto, server::{ReadQuery}, tags};
+
+/// The schema version this build of vault reads and writes.
+/// Must match the version of the last entry in MIGRATIONS.
+pub(crate) const DB_VERSION: u32 = 8;
+
+/// A single schema change, which moves the database from `version - 1` to `version`.
+pub(crate) struct Migration {
+    pub(crate) version: u32,
+    pub(crate) description: &'static str,
+
+    /// Run in order, inside one transaction. One SQL statement each.
+    pub(crate) statements: &'static [&'static str],
+}
+
+/// Every schema change since version 1, in order.
+/// To change the schema, add a Migration here and bump DB_VERSION.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "Keep old versions of edited entries",
+        statements: &["
+            CREATE TABLE entry_revision (
+                entry_timestamp_ms_utc INTEGER,
+                replaced_ms_utc INTEGER,
+                contents BLOB,
+                PRIMARY KEY (entry_timestamp_ms_utc, replaced_ms_utc)
+            )
+        "],
+    },
+    Migration {
+        version: 3,
+        description: "Add encrypted tags",
+        statements: &[
+            "
+            CREATE TABLE entry_tag (
+                id INTEGER PRIMARY KEY,
+                entry_timestamp_ms_utc INTEGER,
+                tag BLOB,
+                tag_hash BLOB
+            )
+            ",
+            "CREATE INDEX entry_tag_entry ON entry_tag (entry_timestamp_ms_utc)",
+            "CREATE INDEX entry_tag_hash ON entry_tag (tag_hash)",
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "Add encrypted attachments",
+        statements: &[
+            "
+            CREATE TABLE attachment (
+                id INTEGER PRIMARY KEY,
+                entry_timestamp_ms_utc INTEGER,
+                name BLOB,
+                key BLOB,
+                contents BLOB
+            )
+            ",
+            "CREATE INDEX attachment_entry ON attachment (entry_timestamp_ms_utc)",
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Add encrypted drafts",
+        statements: &["
+            CREATE TABLE draft (
+                token TEXT PRIMARY KEY,
+                saved_ms_utc INTEGER,
+                contents BLOB,
+                tags BLOB
+            )
+        "],
+    },
+    Migration {
+        version: 6,
+        description: "Record failed logins",
+        statements: &["
+            CREATE TABLE login_failure (
+                id INTEGER PRIMARY KEY,
+                timestamp_ms_utc INTEGER,
+                remote TEXT,
+                path TEXT
+            )
+        "],
+    },
+    Migration {
+        version: 7,
+        description: "Allow two edits of an entry in the same millisecond",
+        statements: &[
+            "
+            CREATE TABLE entry_revision_v7 (
+                id INTEGER PRIMARY KEY,
+                entry_timestamp_ms_utc INTEGER,
+                replaced_ms_utc INTEGER,
+                contents BLOB
+            )
+            ",
+            "
+            INSERT INTO entry_revision_v7 (entry_timestamp_ms_utc, replaced_ms_utc, contents)
+            SELECT entry_timestamp_ms_utc, replaced_ms_utc, contents
+            FROM entry_revision
+            ORDER BY entry_timestamp_ms_utc, replaced_ms_utc
+            ",
+            "DROP TABLE entry_revision",
+            "ALTER TABLE entry_revision_v7 RENAME TO entry_revision",
+            "CREATE INDEX entry_revision_entry ON entry_revision (entry_timestamp_ms_utc)",
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "Index links between entries",
+        statements: &[
+            "
+            CREATE TABLE entry_link (
+                id INTEGER PRIMARY KEY,
+                entry_timestamp_ms_utc INTEGER,
+                target_hash BLOB
+            )
+            ",
+            "CREATE INDEX entry_link_entry ON entry_link (entry_timestamp_ms_utc)",
+            "CREATE INDEX entry_link_target ON entry_link (target_hash)",
+            // Entries whose current contents are in entry_link. Others get indexed when next read.
+            "CREATE TABLE entry_link_indexed (entry_timestamp_ms_utc INTEGER PRIMARY KEY)",
+        ],
+    },
+];
 
-const DB_VERSION: u32 = 1;
+/// Keep the login_failure table from growing forever during an attack.
+const MAX_LOGIN_FAILURES: i64 = 10_000;
 
-pub const SETTING_PUBLIC_KEY: &'static str = "publicKey";
-pub const SETTING_VERSION : &'static str = "version";
+/// SQL for an entry's date (YYYY-MM-DD) in the time zone it was written in.
+const LOCAL_DATE: &str = "date(timestamp_ms_utc / 1000 + offset_utc_mins * 60, 'unixepoch')";
+
+pub const SETTING_PUBLIC_KEY: &str = "publicKey";
+pub const SETTING_VERSION : &str = "version";
+/// The private key, encrypted with a passphrase. Optional.
+pub const SETTING_WRAPPED_KEY: &str = "wrappedPrivateKey";
+/// During `vault rekey`, the new private key, sealed to the old public key.
+/// Lets an interrupted rekey resume with the same new key.
+pub const SETTING_PENDING_KEY: &str = "pendingPrivateKey";
+/// The private key, encrypted with its API token. Only present while the API token is enabled.
+/// Stored as "<salt>:<wrapped key>", where the salt is the one the token was derived with.
+pub const SETTING_API_KEY: &str = "apiWrappedPrivateKey";
+/// An Argon2id hash of the password needed to write. Without one, anyone who can reach the server can write.
+pub const SETTING_WRITE_PASSWORD: &str = "writePasswordHash";
+/// Encrypts the cookie key in `vault serve --session-key-file`, so the file alone is useless.
+pub const SETTING_SESSION_WRAP_KEY: &str = "sessionWrapKey";
+/// "true" if shutting down the server needs a login.
+pub const SETTING_SHUTDOWN_NEEDS_LOGIN: &str = "shutdownNeedsLogin";
 
 pub(crate) fn options(file: impl AsRef<Path>) -> SqliteConnectOptions {
     SqliteConnectOptions::new()
@@ -18,6 +166,21 @@ pub(crate) fn options(file: impl AsRef<Path>) -> SqliteConnectOptions {
     .journal_mode(SqliteJournalMode::Delete)
 }
 
+/// Fail unless the database is at DB_VERSION, since older ones are missing tables.
+pub(crate) async fn check_version(db: &SqlitePool, file: &Path) -> anyhow::Result<()> {
+    if !db.needs_upgrade().await? {
+        return Ok(());
+    }
+    let version = db.get_version().await?;
+    if version > DB_VERSION {
+        bail!("Database version {} is greater than supported version {}", version, DB_VERSION);
+    }
+    bail!(
+        "Database version {} needs an upgrade to version {}. Run: vault upgrade {}",
+        version, DB_VERSION, file.to_string_lossy()
+    )
+}
+
  pub(crate) fn pool(opts: SqliteConnectOptions) -> SqlitePool {
     SqlitePoolOptions::new()
         .max_connections(1)
@@ -29,31 +192,543 @@ pub(crate) fn options(file: impl AsRef<Path>) -> SqliteConnectOptions {
 pub(crate) trait VaultExt {
     async fn get_version(&self) -> anyhow::Result<u32>;
     async fn needs_upgrade(&self) -> anyhow::Result<bool>;
+    /// Apply all pending migrations to bring the database up to DB_VERSION.
+    async fn upgrade(&self) -> anyhow::Result<()>;
+    /// Write a consistent copy of the database to a new file.
+    async fn backup(&self, file: &Path) -> anyhow::Result<()>;
     async fn public_key(&self) -> anyhow::Result<crypto::SealedBoxPublicKey>;
-    async fn get_posts(&self, query: &ReadQuery) -> anyhow::Result<Vec<Entry>>;
+    /// A page of entries for /read. Optionally, only entries with a tag. See crate::tags for `tag_hash`.
+    async fn get_posts(&self, query: &ReadQuery, tag_hash: Option<&[u8]>) -> anyhow::Result<Vec<Entry>>;
+    /// Up to `limit` entries older than `before_ms_utc`, newest first.
+    async fn entries_before(&self, before_ms_utc: Option<i64>, limit: usize) -> anyhow::Result<Vec<Entry>>;
+    /// Every entry, oldest first.
+    async fn all_entries(&self) -> anyhow::Result<Vec<Entry>>;
+    async fn get_entry(&self, timestamp_ms_utc: i64) -> anyhow::Result<Option<Entry>>;
     async fn write_entry(&self, entry: Entry) -> anyhow::Result<()>;
+    /// Like write_entry(), but also writes its tags and attachments, in the same transaction.
+    /// Tag names should be sealed like entry contents. Attachments are written for `entry`,
+    /// whatever their entry_timestamp_ms_utc.
+    async fn write_full_entry(&self, entry: Entry, tags: &[Vec<u8>], attachments: &[Attachment]) -> anyhow::Result<()>;
+    /// Replace the encrypted contents and tags of an entry, in one transaction, keeping the old
+    /// contents as a Revision. Returns false if there's no such entry.
+    async fn revise_tagged_entry(&self, timestamp_ms_utc: i64, contents: Vec<u8>, tags: &[Vec<u8>], edited_ms_utc: i64) -> anyhow::Result<bool>;
+    /// Previous versions of an entry, oldest first.
+    async fn get_revisions(&self, timestamp_ms_utc: i64) -> anyhow::Result<Vec<Revision>>;
+    /// How many times each entry in a time range has been edited. Unedited entries are omitted.
+    async fn revision_counts(&self, from_ms_utc: i64, to_ms_utc: i64) -> anyhow::Result<HashMap<i64, i64>>;
+    /// How many entries were written in each month (YYYY-MM), oldest first.
+    /// Months are in the time zone each entry was written in.
+    async fn month_counts(&self) -> anyhow::Result<Vec<(String, i64)>>;
+    /// How many entries were written on each day (YYYY-MM-DD) of a month (YYYY-MM), oldest first.
+    async fn day_counts(&self, month: &str) -> anyhow::Result<Vec<(String, i64)>>;
+    /// The timestamps of the entries just before and after this one.
+    async fn neighbors(&self, timestamp_ms_utc: i64) -> anyhow::Result<(Option<i64>, Option<i64>)>;
+    /// Entries written on the same month and day as `date`, in earlier years. Newest first.
+    async fn entries_on_this_day(&self, date: NaiveDate) -> anyhow::Result<Vec<Entry>>;
+    /// Tags for entries in a time range.
+    async fn tags_between(&self, from_ms_utc: i64, to_ms_utc: i64) -> anyhow::Result<Vec<Tag>>;
+    /// Each indexed tag (still sealed), with how many entries have it.
+    async fn tag_counts(&self) -> anyhow::Result<Vec<(Vec<u8>, i64)>>;
+    /// Fill in the tag_hash of tags that were written without the private key.
+    /// Returns how many were indexed.
+    async fn index_tags(&self, key: &crypto::SealedBoxPrivateKey) -> anyhow::Result<usize>;
+    /// Entries written or edited since their links were last indexed. See crate::links.
+    async fn unindexed_link_entries(&self) -> anyhow::Result<Vec<Entry>>;
+    /// Replace the indexed links from `entry`, unless it's been edited since it was read.
+    /// Returns false if it was.
+    async fn write_links(&self, entry: &Entry, target_hashes: &[Vec<u8>]) -> anyhow::Result<bool>;
+    /// Links to any of these targets, oldest source entry first.
+    async fn links_to(&self, target_hashes: &[Vec<u8>]) -> anyhow::Result<Vec<Link>>;
+    async fn get_attachment(&self, id: i64) -> anyhow::Result<Option<Attachment>>;
+    /// Attachments for entries in a time range, without their (possibly large) contents.
+    async fn attachments_between(&self, from_ms_utc: i64, to_ms_utc: i64) -> anyhow::Result<Vec<AttachmentInfo>>;
+    /// Create or replace a draft. See Draft.
+    async fn save_draft(&self, draft: &Draft) -> anyhow::Result<()>;
+    async fn get_draft(&self, token: &str) -> anyhow::Result<Option<Draft>>;
+    /// The most recently saved draft.
+    async fn latest_draft(&self) -> anyhow::Result<Option<Draft>>;
+    async fn delete_draft(&self, token: &str) -> anyhow::Result<()>;
+
+    async fn record_login_failure(&self, failure: &LoginFailure) -> anyhow::Result<()>;
+    async fn login_failure_count(&self) -> anyhow::Result<i64>;
+    /// Newest first.
+    async fn recent_login_failures(&self, limit: usize) -> anyhow::Result<Vec<LoginFailure>>;
+    /// Deletes the entry and all of its revisions, tags and attachments. Returns false if there's no such entry.
+    async fn delete_entry(&self, timestamp_ms_utc: i64) -> anyhow::Result<bool>;
+    /// Like write_full_entry() without attachments, but returns false instead of failing if there's already an entry at that timestamp.
+    async fn write_entry_if_new(&self, entry: &Entry, tags: &[Vec<u8>]) -> anyhow::Result<bool>;
+    async fn read_setting(&self, key: &str) -> anyhow::Result<Option<String>>;
     async fn write_setting(&self, key: &str, value: &str) -> anyhow::Result<()>;
+    async fn delete_setting(&self, key: &str) -> anyhow::Result<()>;
+
+    /// Re-encrypt everything from the `old` key to the `new` one, in a single transaction.
+    /// Replaces the passphrase-wrapped key with `wrapped`, or removes it if None. Disables the API token.
+    /// Returns the number of entries re-encrypted.
+    async fn rekey(
+        &self,
+        old: &crypto::SealedBoxPrivateKey,
+        new: &crypto::SealedBoxPrivateKey,
+        wrapped: Option<&str>,
+    ) -> anyhow::Result<usize>;
+
+    /// Get the private key from either its base58 form or the passphrase that unwraps it.
+    /// Returns None if `secret` is neither.
+    async fn unlock(&self, secret: &str) -> anyhow::Result<Option<crypto::SealedBoxPrivateKey>>;
+    /// Enable the API bearer token and return it. If it's already enabled, returns the same one.
+    /// Once revoked, enabling it again makes a new one.
+    async fn enable_api_token(&self, key: &crypto::SealedBoxPrivateKey) -> anyhow::Result<String>;
+    /// Get the private key from an API bearer token. Returns None if the token isn't enabled.
+    async fn unlock_api_token(&self, token: &str) -> anyhow::Result<Option<crypto::SealedBoxPrivateKey>>;
 }
 
 #[async_trait]
 impl VaultExt for sqlx::Pool<sqlx::Sqlite> {
     
-    async fn get_posts(&self, query: &ReadQuery) -> anyhow::Result<Vec<Entry>> {
+    async fn get_posts(&self, query: &ReadQuery, tag_hash: Option<&[u8]>) -> anyhow::Result<Vec<Entry>> {
+        // When paging back, fetch the entries nearest the cursor, then put them back in order.
+        let ascending = query.chronological != query.paging_back();
+        let sql = format!("
+                SELECT timestamp_ms_utc, contents, offset_utc_mins
+                FROM entry
+                WHERE (?1 IS NULL OR timestamp_ms_utc IN (
+                    SELECT entry_timestamp_ms_utc FROM entry_tag WHERE tag_hash = ?1
+                ))
+                AND (?2 IS NULL OR timestamp_ms_utc < ?2)
+                AND (?3 IS NULL OR timestamp_ms_utc > ?3)
+                AND (?4 IS NULL OR {local_date} >= ?4)
+                AND (?5 IS NULL OR {local_date} <= ?5)
+                ORDER BY timestamp_ms_utc {order}
+                LIMIT ?6
+            ",
+            local_date = LOCAL_DATE,
+            order = if ascending { "ASC" } else { "DESC" },
+        );
+        let mut entries: Vec<Entry> = sqlx::query_as(&sql)
+            .bind(tag_hash)
+            .bind(query.before)
+            .bind(query.after)
+            .bind(query.from.map(|d| d.to_string()))
+            .bind(query.to.map(|d| d.to_string()))
+            .bind(query.limit() as i64)
+            .fetch_all(self)
+            .await?;
+        if query.paging_back() {
+            entries.reverse();
+        }
+        Ok(entries)
+    }
+
+    async fn entries_before(&self, before_ms_utc: Option<i64>, limit: usize) -> anyhow::Result<Vec<Entry>> {
+        let entries = sqlx::query_as("
+                SELECT timestamp_ms_utc, contents, offset_utc_mins
+                FROM entry
+                WHERE timestamp_ms_utc < ?
+                ORDER BY timestamp_ms_utc DESC
+                LIMIT ?
+            ")
+            .bind(before_ms_utc.unwrap_or(i64::MAX))
+            .bind(limit as i64)
+            .fetch_all(self)
+            .await?;
+        Ok(entries)
+    }
+
+    async fn all_entries(&self) -> anyhow::Result<Vec<Entry>> {
         let entries = sqlx::query_as("
                 SELECT timestamp_ms_utc, contents, offset_utc_mins
                 FROM entry
+                ORDER BY timestamp_ms_utc ASC
+            ")
+            .fetch_all(self)
+            .await?;
+        Ok(entries)
+    }
+
+    async fn get_entry(&self, timestamp_ms_utc: i64) -> anyhow::Result<Option<Entry>> {
+        let entry = sqlx::query_as("
+                SELECT timestamp_ms_utc, contents, offset_utc_mins
+                FROM entry
+                WHERE timestamp_ms_utc = ?
+            ")
+            .bind(timestamp_ms_utc)
+            .fetch_optional(self)
+            .await?;
+        Ok(entry)
+    }
+
+    async fn revise_tagged_entry(&self, timestamp_ms_utc: i64, contents: Vec<u8>, tags: &[Vec<u8>], edited_ms_utc: i64) -> anyhow::Result<bool> {
+        let mut tx = self.begin().await?;
+
+        let inserted = sqlx::query("
+                INSERT INTO entry_revision (entry_timestamp_ms_utc, replaced_ms_utc, contents)
+                SELECT timestamp_ms_utc, ?, contents
+                FROM entry
+                WHERE timestamp_ms_utc = ?
+            ")
+            .bind(edited_ms_utc)
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        if inserted.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query("UPDATE entry SET contents = ? WHERE timestamp_ms_utc = ?")
+            .bind(contents)
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM entry_link_indexed WHERE entry_timestamp_ms_utc = ?")
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM entry_tag WHERE entry_timestamp_ms_utc = ?")
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        insert_tags(&mut tx, timestamp_ms_utc, tags).await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn get_revisions(&self, timestamp_ms_utc: i64) -> anyhow::Result<Vec<Revision>> {
+        let revisions = sqlx::query_as("
+                SELECT id, entry_timestamp_ms_utc, replaced_ms_utc, contents
+                FROM entry_revision
+                WHERE entry_timestamp_ms_utc = ?
+                ORDER BY replaced_ms_utc ASC, id ASC
+            ")
+            .bind(timestamp_ms_utc)
+            .fetch_all(self)
+            .await?;
+        Ok(revisions)
+    }
+
+    async fn revision_counts(&self, from_ms_utc: i64, to_ms_utc: i64) -> anyhow::Result<HashMap<i64, i64>> {
+        let counts: Vec<(i64, i64)> = sqlx::query_as("
+                SELECT entry_timestamp_ms_utc, COUNT(*)
+                FROM entry_revision
+                WHERE entry_timestamp_ms_utc BETWEEN ? AND ?
+                GROUP BY entry_timestamp_ms_utc
+            ")
+            .bind(from_ms_utc)
+            .bind(to_ms_utc)
+            .fetch_all(self)
+            .await?;
+        Ok(counts.into_iter().collect())
+    }
+
+    async fn month_counts(&self) -> anyhow::Result<Vec<(String, i64)>> {
+        let sql = format!("
+                SELECT substr({local_date}, 1, 7) AS month, COUNT(*)
+                FROM entry
+                GROUP BY month
+                ORDER BY month
+            ",
+            local_date = LOCAL_DATE,
+        );
+        let counts = sqlx::query_as(&sql)
+            .fetch_all(self)
+            .await?;
+        Ok(counts)
+    }
+
+    async fn day_counts(&self, month: &str) -> anyhow::Result<Vec<(String, i64)>> {
+        let sql = format!("
+                SELECT {local_date} AS day, COUNT(*)
+                FROM entry
+                WHERE substr(day, 1, 7) = ?
+                GROUP BY day
+                ORDER BY day
+            ",
+            local_date = LOCAL_DATE,
+        );
+        let counts = sqlx::query_as(&sql)
+            .bind(month)
+            .fetch_all(self)
+            .await?;
+        Ok(counts)
+    }
+
+    async fn neighbors(&self, timestamp_ms_utc: i64) -> anyhow::Result<(Option<i64>, Option<i64>)> {
+        let (before, after) = sqlx::query_as("
+                SELECT
+                    (SELECT MAX(timestamp_ms_utc) FROM entry WHERE timestamp_ms_utc < ?1),
+                    (SELECT MIN(timestamp_ms_utc) FROM entry WHERE timestamp_ms_utc > ?1)
+            ")
+            .bind(timestamp_ms_utc)
+            .fetch_one(self)
+            .await?;
+        Ok((before, after))
+    }
+
+    async fn entries_on_this_day(&self, date: NaiveDate) -> anyhow::Result<Vec<Entry>> {
+        let sql = format!("
+                SELECT timestamp_ms_utc, contents, offset_utc_mins
+                FROM entry
+                WHERE substr({local_date}, 6) = ?
+                AND {local_date} < ?
                 ORDER BY timestamp_ms_utc DESC
-                LIMIT ?, ?
+            ",
+            local_date = LOCAL_DATE,
+        );
+        let entries = sqlx::query_as(&sql)
+            .bind(date.format("%m-%d").to_string())
+            .bind(format!("{:04}-01-01", date.year()))
+            .fetch_all(self)
+            .await?;
+        Ok(entries)
+    }
+
+    async fn tags_between(&self, from_ms_utc: i64, to_ms_utc: i64) -> anyhow::Result<Vec<Tag>> {
+        let tags = sqlx::query_as("
+                SELECT id, entry_timestamp_ms_utc, tag
+                FROM entry_tag
+                WHERE entry_timestamp_ms_utc BETWEEN ? AND ?
+            ")
+            .bind(from_ms_utc)
+            .bind(to_ms_utc)
+            .fetch_all(self)
+            .await?;
+        Ok(tags)
+    }
+
+    async fn tag_counts(&self) -> anyhow::Result<Vec<(Vec<u8>, i64)>> {
+        let counts = sqlx::query_as("
+                SELECT MIN(tag), COUNT(*)
+                FROM entry_tag
+                WHERE tag_hash IS NOT NULL
+                GROUP BY tag_hash
+            ")
+            .fetch_all(self)
+            .await?;
+        Ok(counts)
+    }
+
+    async fn index_tags(&self, key: &crypto::SealedBoxPrivateKey) -> anyhow::Result<usize> {
+        let tags: Vec<Tag> = sqlx::query_as("
+                SELECT id, entry_timestamp_ms_utc, tag
+                FROM entry_tag
+                WHERE tag_hash IS NULL
+            ")
+            .fetch_all(self)
+            .await?;
+        if tags.is_empty() {
+            return Ok(0);
+        }
+
+        let mut tx = self.begin().await?;
+        for tag in &tags {
+            let name = key.decrypt(&tag.tag)?;
+            sqlx::query("UPDATE entry_tag SET tag_hash = ? WHERE id = ?")
+                .bind(key.keyed_hash(tags::HASH_CONTEXT, &name))
+                .bind(tag.id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(tags.len())
+    }
+
+    async fn unindexed_link_entries(&self) -> anyhow::Result<Vec<Entry>> {
+        let entries = sqlx::query_as("
+                SELECT timestamp_ms_utc, offset_utc_mins, contents
+                FROM entry
+                WHERE timestamp_ms_utc NOT IN (SELECT entry_timestamp_ms_utc FROM entry_link_indexed)
             ")
-            .bind(query.offset.map(|u| u as i64).unwrap_or(0))
-            .bind(query.limit.map(|u| u as i64).unwrap_or(50))
             .fetch_all(self)
             .await?;
         Ok(entries)
     }
 
+    async fn write_links(&self, entry: &Entry, target_hashes: &[Vec<u8>]) -> anyhow::Result<bool> {
+        let mut tx = self.begin().await?;
+        let indexed = sqlx::query("
+                INSERT OR IGNORE INTO entry_link_indexed (entry_timestamp_ms_utc)
+                SELECT timestamp_ms_utc FROM entry WHERE timestamp_ms_utc = ? AND contents = ?
+            ")
+            .bind(entry.timestamp_ms_utc)
+            .bind(&entry.contents)
+            .execute(&mut tx)
+            .await?;
+        if indexed.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query("DELETE FROM entry_link WHERE entry_timestamp_ms_utc = ?")
+            .bind(entry.timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        for hash in target_hashes {
+            sqlx::query("INSERT INTO entry_link (entry_timestamp_ms_utc, target_hash) VALUES (?, ?)")
+                .bind(entry.timestamp_ms_utc)
+                .bind(hash)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn links_to(&self, target_hashes: &[Vec<u8>]) -> anyhow::Result<Vec<Link>> {
+        if target_hashes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sql = format!("
+                SELECT DISTINCT l.entry_timestamp_ms_utc, e.offset_utc_mins, l.target_hash
+                FROM entry_link AS l
+                JOIN entry AS e ON e.timestamp_ms_utc = l.entry_timestamp_ms_utc
+                WHERE l.target_hash IN ({})
+                ORDER BY l.entry_timestamp_ms_utc ASC
+            ",
+            vec!["?"; target_hashes.len()].join(","),
+        );
+        let mut query = sqlx::query_as(&sql);
+        for hash in target_hashes {
+            query = query.bind(hash);
+        }
+        Ok(query.fetch_all(self).await?)
+    }
+
+    async fn get_attachment(&self, id: i64) -> anyhow::Result<Option<Attachment>> {
+        let attachment = sqlx::query_as("
+                SELECT entry_timestamp_ms_utc, name, key, contents
+                FROM attachment
+                WHERE id = ?
+            ")
+            .bind(id)
+            .fetch_optional(self)
+            .await?;
+        Ok(attachment)
+    }
+
+    async fn attachments_between(&self, from_ms_utc: i64, to_ms_utc: i64) -> anyhow::Result<Vec<AttachmentInfo>> {
+        let attachments = sqlx::query_as("
+                SELECT id, entry_timestamp_ms_utc, name
+                FROM attachment
+                WHERE entry_timestamp_ms_utc BETWEEN ? AND ?
+                ORDER BY id
+            ")
+            .bind(from_ms_utc)
+            .bind(to_ms_utc)
+            .fetch_all(self)
+            .await?;
+        Ok(attachments)
+    }
+
+    async fn save_draft(&self, draft: &Draft) -> anyhow::Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO draft (token, saved_ms_utc, contents, tags) VALUES (?, ?, ?, ?)")
+            .bind(&draft.token)
+            .bind(draft.saved_ms_utc)
+            .bind(&draft.contents)
+            .bind(&draft.tags)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_draft(&self, token: &str) -> anyhow::Result<Option<Draft>> {
+        let draft = sqlx::query_as("SELECT token, saved_ms_utc, contents, tags FROM draft WHERE token = ?")
+            .bind(token)
+            .fetch_optional(self)
+            .await?;
+        Ok(draft)
+    }
+
+    async fn latest_draft(&self) -> anyhow::Result<Option<Draft>> {
+        let draft = sqlx::query_as("
+                SELECT token, saved_ms_utc, contents, tags
+                FROM draft
+                ORDER BY saved_ms_utc DESC
+                LIMIT 1
+            ")
+            .fetch_optional(self)
+            .await?;
+        Ok(draft)
+    }
+
+    async fn delete_draft(&self, token: &str) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM draft WHERE token = ?")
+            .bind(token)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_login_failure(&self, failure: &LoginFailure) -> anyhow::Result<()> {
+        let mut tx = self.begin().await?;
+        sqlx::query("INSERT INTO login_failure (timestamp_ms_utc, remote, path) VALUES (?, ?, ?)")
+            .bind(failure.timestamp_ms_utc)
+            .bind(&failure.remote)
+            .bind(&failure.path)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM login_failure WHERE id <= (SELECT MAX(id) FROM login_failure) - ?")
+            .bind(MAX_LOGIN_FAILURES)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn login_failure_count(&self) -> anyhow::Result<i64> {
+        let (count,): (i64,) = query_as("SELECT COUNT(*) FROM login_failure")
+            .fetch_one(self)
+            .await?;
+        Ok(count)
+    }
+
+    async fn recent_login_failures(&self, limit: usize) -> anyhow::Result<Vec<LoginFailure>> {
+        let failures = sqlx::query_as("
+                SELECT timestamp_ms_utc, remote, path
+                FROM login_failure
+                ORDER BY id DESC
+                LIMIT ?
+            ")
+            .bind(limit as i64)
+            .fetch_all(self)
+            .await?;
+        Ok(failures)
+    }
+
+    async fn delete_entry(&self, timestamp_ms_utc: i64) -> anyhow::Result<bool> {
+        let mut tx = self.begin().await?;
+        sqlx::query("DELETE FROM attachment WHERE entry_timestamp_ms_utc = ?")
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM entry_revision WHERE entry_timestamp_ms_utc = ?")
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM entry_tag WHERE entry_timestamp_ms_utc = ?")
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM entry_link WHERE entry_timestamp_ms_utc = ?")
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM entry_link_indexed WHERE entry_timestamp_ms_utc = ?")
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        let result = sqlx::query("DELETE FROM entry WHERE timestamp_ms_utc = ?")
+            .bind(timestamp_ms_utc)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
     async fn write_entry(&self, entry: Entry) -> anyhow::Result<()> {
+        self.write_full_entry(entry, &[], &[]).await
+    }
+
+    async fn write_full_entry(&self, entry: Entry, tags: &[Vec<u8>], attachments: &[Attachment]) -> anyhow::Result<()> {
         let Entry{timestamp_ms_utc, offset_utc_mins, contents} = entry;
+        let mut tx = self.begin().await?;
         sqlx::query("
                 INSERT INTO entry(timestamp_ms_utc, offset_utc_mins, contents)
                 VALUES(?,?,?)
@@ -61,11 +736,43 @@ impl VaultExt for sqlx::Pool<sqlx::Sqlite> {
             .bind(timestamp_ms_utc)
             .bind(offset_utc_mins)
             .bind(contents)
-            .execute(self).await?;
+            .execute(&mut tx).await?;
+        insert_tags(&mut tx, timestamp_ms_utc, tags).await?;
+        for attachment in attachments {
+            sqlx::query("
+                    INSERT INTO attachment (entry_timestamp_ms_utc, name, key, contents)
+                    VALUES (?, ?, ?, ?)
+                ")
+                .bind(timestamp_ms_utc)
+                .bind(&attachment.name)
+                .bind(&attachment.key)
+                .bind(&attachment.contents)
+                .execute(&mut tx).await?;
+        }
+        tx.commit().await?;
 
         Ok(())
     }
 
+    async fn write_entry_if_new(&self, entry: &Entry, tags: &[Vec<u8>]) -> anyhow::Result<bool> {
+        let mut tx = self.begin().await?;
+        let result = sqlx::query("
+                INSERT OR IGNORE INTO entry(timestamp_ms_utc, offset_utc_mins, contents)
+                VALUES(?,?,?)
+            ")
+            .bind(entry.timestamp_ms_utc)
+            .bind(entry.offset_utc_mins)
+            .bind(&entry.contents)
+            .execute(&mut tx).await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        insert_tags(&mut tx, entry.timestamp_ms_utc, tags).await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
     async fn get_version(&self) -> anyhow::Result<u32> {
         let (version_str,): (String,) = query_as("SELECT value FROM settings WHERE key = ?")
             .bind(SETTING_VERSION)
@@ -76,18 +783,25 @@ impl VaultExt for sqlx::Pool<sqlx::Sqlite> {
         Ok(version)
     }
 
-    // TODO: Separate out the println bits into a different method.
     async fn needs_upgrade(&self) -> anyhow::Result<bool> {
         let version = self.get_version().await?;
-        if version == DB_VERSION {
-            return Ok(false);
-        } else if DB_VERSION > version {
-            println!("Database version {} needs upgrade to version {}", version, DB_VERSION);
-            return Ok(true);
-        } else {
-            println!("Database version {} is greater than supported version {}", version, DB_VERSION);
-            return Ok(true);
+        Ok(version != DB_VERSION)
+    }
+
+    async fn upgrade(&self) -> anyhow::Result<()> {
+        apply_migrations(self, MIGRATIONS).await
+    }
+
+    async fn backup(&self, file: &Path) -> anyhow::Result<()> {
+        if file.exists() {
+            bail!("Backup file '{}' already exists", file.to_string_lossy());
         }
+        sqlx::query("VACUUM INTO ?")
+            .bind(file.to_string_lossy().to_string())
+            .execute(self)
+            .await
+            .context("Backing up database")?;
+        Ok(())
     }
 
     async fn public_key(&self) -> anyhow::Result<crypto::SealedBoxPublicKey> {
@@ -100,14 +814,196 @@ impl VaultExt for sqlx::Pool<sqlx::Sqlite> {
         Ok(key)
     }
 
+    async fn read_setting(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let value: Option<(String,)> = query_as("SELECT value FROM settings WHERE key = ?")
+            .bind(key)
+            .fetch_optional(self)
+            .await?;
+        Ok(value.map(|(v,)| v))
+    }
+
     async fn write_setting(&self, key: &str, value: &str) -> anyhow::Result<()> {
-        sqlx::query("INSERT INTO settings (key, value) VALUES(?,?)")
+        sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES(?,?)")
             .bind(key)
             .bind(value)
             .execute(self)
             .await?;
         Ok(())
     }
+
+    async fn delete_setting(&self, key: &str) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM settings WHERE key = ?")
+            .bind(key)
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn rekey(
+        &self,
+        old: &crypto::SealedBoxPrivateKey,
+        new: &crypto::SealedBoxPrivateKey,
+        wrapped: Option<&str>,
+    ) -> anyhow::Result<usize> {
+        let mut tx = self.begin().await?;
+
+        let entries: Vec<Entry> = sqlx::query_as("SELECT timestamp_ms_utc, contents, offset_utc_mins FROM entry")
+            .fetch_all(&mut tx)
+            .await?;
+        for entry in &entries {
+            let plain = old.decrypt(&entry.contents)
+                .with_context(|| format!("Decrypting entry {}", entry.timestamp_ms_utc))?;
+            sqlx::query("UPDATE entry SET contents = ? WHERE timestamp_ms_utc = ?")
+                .bind(new.public().encrypt(&plain))
+                .bind(entry.timestamp_ms_utc)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        let revisions: Vec<Revision> = sqlx::query_as("
+                SELECT id, entry_timestamp_ms_utc, replaced_ms_utc, contents FROM entry_revision
+            ")
+            .fetch_all(&mut tx)
+            .await?;
+        for revision in &revisions {
+            let plain = old.decrypt(&revision.contents)
+                .with_context(|| format!("Decrypting revision of entry {}", revision.entry_timestamp_ms_utc))?;
+            sqlx::query("UPDATE entry_revision SET contents = ? WHERE id = ?")
+                .bind(new.public().encrypt(&plain))
+                .bind(revision.id)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        // Tag hashes are keyed with the private key, so they need to be indexed again.
+        let tags: Vec<Tag> = sqlx::query_as("SELECT id, entry_timestamp_ms_utc, tag FROM entry_tag")
+            .fetch_all(&mut tx)
+            .await?;
+        for tag in &tags {
+            let plain = old.decrypt(&tag.tag)
+                .with_context(|| format!("Decrypting tag of entry {}", tag.entry_timestamp_ms_utc))?;
+            sqlx::query("UPDATE entry_tag SET tag = ?, tag_hash = NULL WHERE id = ?")
+                .bind(new.public().encrypt(&plain))
+                .bind(tag.id)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        // Link hashes are keyed too. Rather than parse every entry again here, they're indexed when next read.
+        sqlx::query("DELETE FROM entry_link").execute(&mut tx).await?;
+        sqlx::query("DELETE FROM entry_link_indexed").execute(&mut tx).await?;
+
+        // Only each attachment's key and name are sealed. Their contents don't need to change.
+        let attachments: Vec<(i64, i64, Vec<u8>, Vec<u8>)> = sqlx::query_as("
+                SELECT id, entry_timestamp_ms_utc, name, key FROM attachment
+            ")
+            .fetch_all(&mut tx)
+            .await?;
+        for (id, entry_timestamp_ms_utc, name, key) in &attachments {
+            let name = old.decrypt(name)
+                .with_context(|| format!("Decrypting attachment name of entry {}", entry_timestamp_ms_utc))?;
+            let key = old.decrypt(key)
+                .with_context(|| format!("Decrypting attachment key of entry {}", entry_timestamp_ms_utc))?;
+            sqlx::query("UPDATE attachment SET name = ?, key = ? WHERE id = ?")
+                .bind(new.public().encrypt(&name))
+                .bind(new.public().encrypt(&key))
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        let drafts: Vec<Draft> = sqlx::query_as("SELECT token, saved_ms_utc, contents, tags FROM draft")
+            .fetch_all(&mut tx)
+            .await?;
+        for draft in &drafts {
+            let contents = old.decrypt(&draft.contents).context("Decrypting draft")?;
+            let tags = old.decrypt(&draft.tags).context("Decrypting draft tags")?;
+            sqlx::query("UPDATE draft SET contents = ?, tags = ? WHERE token = ?")
+                .bind(new.public().encrypt(&contents))
+                .bind(new.public().encrypt(&tags))
+                .bind(&draft.token)
+                .execute(&mut tx)
+                .await?;
+        }
+
+        sqlx::query("UPDATE settings SET value = ? WHERE key = ?")
+            .bind(new.public().to_string())
+            .bind(SETTING_PUBLIC_KEY)
+            .execute(&mut tx)
+            .await?;
+        match wrapped {
+            Some(wrapped) => sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES(?,?)")
+                .bind(SETTING_WRAPPED_KEY)
+                .bind(wrapped),
+            None => sqlx::query("DELETE FROM settings WHERE key = ?")
+                .bind(SETTING_WRAPPED_KEY),
+        }.execute(&mut tx).await?;
+        sqlx::query("DELETE FROM settings WHERE key = ?")
+            .bind(SETTING_PENDING_KEY)
+            .execute(&mut tx)
+            .await?;
+        // The API token is derived from the old private key:
+        sqlx::query("DELETE FROM settings WHERE key = ?")
+            .bind(SETTING_API_KEY)
+            .execute(&mut tx)
+            .await?;
+        // Saved sessions hold the old private key. Without this, the next start makes a new cookie key.
+        sqlx::query("DELETE FROM settings WHERE key = ?")
+            .bind(SETTING_SESSION_WRAP_KEY)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(entries.len())
+    }
+
+    async fn unlock(&self, secret: &str) -> anyhow::Result<Option<crypto::SealedBoxPrivateKey>> {
+        let public_key = self.public_key().await?;
+
+        if let Ok(key) = crypto::SealedBoxPrivateKey::from_base58(secret) {
+            if key.public() == &public_key {
+                return Ok(Some(key));
+            }
+        }
+
+        let wrapped = match self.read_setting(SETTING_WRAPPED_KEY).await? {
+            Some(w) => w,
+            None => return Ok(None),
+        };
+
+        let passphrase = secret.to_string();
+        let key = crypto::argon2(move || crypto::SealedBoxPrivateKey::from_wrapped(&wrapped, &passphrase)).await;
+
+        match key {
+            Ok(key) if key.public() == &public_key => Ok(Some(key)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn enable_api_token(&self, key: &crypto::SealedBoxPrivateKey) -> anyhow::Result<String> {
+        let existing = self.read_setting(SETTING_API_KEY).await?;
+        let salt = match existing.as_deref().and_then(|setting| setting.split_once(':')) {
+            Some((salt, _)) => bs58::decode(salt).into_vec().context("Error parsing API token salt")?,
+            None => sodiumoxide::randombytes::randombytes(16),
+        };
+        let token = key.api_token(&salt);
+        let setting = format!("{}:{}", bs58::encode(&salt).into_string(), key.wrap_with_token(&token)?);
+        self.write_setting(SETTING_API_KEY, &setting).await?;
+        Ok(token)
+    }
+
+    async fn unlock_api_token(&self, token: &str) -> anyhow::Result<Option<crypto::SealedBoxPrivateKey>> {
+        let setting = match self.read_setting(SETTING_API_KEY).await? {
+            Some(setting) => setting,
+            None => return Ok(None),
+        };
+        // Tokens enabled before there was a salt are just the wrapped key:
+        let wrapped = setting.rsplit(':').next().unwrap_or_default();
+        match crypto::SealedBoxPrivateKey::from_token_wrapped(wrapped, token) {
+            Ok(key) if key.public() == &self.public_key().await? => Ok(Some(key)),
+            _ => Ok(None),
+        }
+    }
 }
 
 
@@ -123,15 +1019,174 @@ pub(crate) struct Entry {
 
 }
 
+/// A wiki link from an Entry. See crate::links.
+#[derive(FromRow)]
+pub(crate) struct Link {
+    /// The entry with the link.
+    pub(crate) entry_timestamp_ms_utc: i64,
+    pub(crate) offset_utc_mins: i32,
+    /// Hashed like tags, but with links::HASH_CONTEXT.
+    pub(crate) target_hash: Vec<u8>,
+}
 
+impl Link {
+    /// The time of the linking entry, in the time zone it was written in.
+    pub(crate) fn local_time(&self) -> DateTime<FixedOffset> {
+        FixedOffset::east(self.offset_utc_mins * 60).timestamp_millis(self.entry_timestamp_ms_utc)
+    }
+}
 
-pub(crate) async fn create_db(file_name: impl AsRef<Path>) -> anyhow::Result<sqlx::Pool<sqlx::sqlite::Sqlite>> {
-    use sqlx::{Executor};
+/// A previous version of an Entry, saved when it was edited.
+#[derive(FromRow)]
+pub(crate) struct Revision {
+    pub(crate) id: i64,
+    pub(crate) entry_timestamp_ms_utc: i64,
 
-    if file_name.as_ref().exists() {
-        bail!("Database '{}' already exists", file_name.as_ref().to_string_lossy());
+    /// When this version was replaced by a newer one.
+    /// (The first version was written at entry_timestamp_ms_utc.)
+    pub(crate) replaced_ms_utc: i64,
+
+    /// Encrypted, like Entry.contents.
+    pub(crate) contents: Vec<u8>,
+}
+
+/// Text from the write page that hasn't been submitted yet. It's saved periodically while writing,
+/// so it survives a closed tab or a browser crash.
+#[derive(FromRow)]
+pub(crate) struct Draft {
+    /// Random, and only known to the write page that saves the draft.
+    pub(crate) token: String,
+    pub(crate) saved_ms_utc: i64,
+
+    /// The post, sealed like Entry.contents.
+    pub(crate) contents: Vec<u8>,
+
+    /// The tag field, sealed like Entry.contents.
+    pub(crate) tags: Vec<u8>,
+}
+
+/// A wrong password, private key, or token, for `vault access` to report.
+#[derive(FromRow)]
+pub(crate) struct LoginFailure {
+    pub(crate) timestamp_ms_utc: i64,
+    /// The IP address it came from, or "unknown" for a Unix socket.
+    pub(crate) remote: String,
+    pub(crate) path: String,
+}
+
+/// A file attached to an Entry.
+#[derive(FromRow)]
+pub(crate) struct Attachment {
+    pub(crate) entry_timestamp_ms_utc: i64,
+
+    /// The file name, sealed like Entry.contents.
+    pub(crate) name: Vec<u8>,
+
+    /// A crypto::StreamKey, sealed like Entry.contents.
+    pub(crate) key: Vec<u8>,
+
+    /// The file, encrypted with `key`.
+    pub(crate) contents: Vec<u8>,
+}
+
+/// An Attachment, without its contents.
+#[derive(FromRow)]
+pub(crate) struct AttachmentInfo {
+    pub(crate) id: i64,
+    pub(crate) entry_timestamp_ms_utc: i64,
+
+    /// The file name, sealed like Entry.contents.
+    pub(crate) name: Vec<u8>,
+}
+
+/// A tag on an Entry. See crate::tags.
+#[derive(FromRow)]
+pub(crate) struct Tag {
+    pub(crate) id: i64,
+    pub(crate) entry_timestamp_ms_utc: i64,
+
+    /// The tag name, sealed like Entry.contents.
+    /// The tag_hash column is only used for lookups, and is NULL until VaultExt::index_tags().
+    pub(crate) tag: Vec<u8>,
+}
+
+impl Entry {
+    /// The time of the entry, in the time zone it was written in.
+    pub(crate) fn local_time(&self) -> DateTime<FixedOffset> {
+        FixedOffset::east(self.offset_utc_mins * 60).timestamp_millis(self.timestamp_ms_utc)
     }
-    let db = pool(options(file_name).create_if_missing(true));
+}
+
+
+
+/// Migrations that still need to run on a database at `version`.
+pub(crate) fn pending_migrations(version: u32) -> impl Iterator<Item=&'static Migration> {
+    MIGRATIONS.iter().filter(move |m| m.version > version)
+}
+
+/// Where `vault upgrade` saves a copy of a database before migrating it: "<file>.v<version>.bak",
+/// or "<file>.v<version>.<n>.bak" if an earlier attempt at the same upgrade left one behind.
+pub(crate) fn backup_path(file_name: impl AsRef<Path>, version: u32) -> PathBuf {
+    (0..)
+        .map(|attempt| {
+            let mut name = file_name.as_ref().as_os_str().to_owned();
+            match attempt {
+                0 => name.push(format!(".v{}.bak", version)),
+                n => name.push(format!(".v{}.{}.bak", version, n)),
+            }
+            PathBuf::from(name)
+        })
+        .find(|path| !path.exists())
+        .expect("some backup name is free")
+}
+
+async fn insert_tags(tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>, timestamp_ms_utc: i64, tags: &[Vec<u8>]) -> anyhow::Result<()> {
+    for tag in tags {
+        sqlx::query("INSERT INTO entry_tag (entry_timestamp_ms_utc, tag) VALUES (?, ?)")
+            .bind(timestamp_ms_utc)
+            .bind(tag)
+            .execute(&mut *tx)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn apply_migrations(db: &SqlitePool, migrations: &[Migration]) -> anyhow::Result<()> {
+    let start = db.get_version().await?;
+    let latest = migrations.last().map(|m| m.version).unwrap_or(start);
+    if start > latest {
+        bail!("Database version {} is greater than supported version {}", start, latest);
+    }
+
+    let mut version = start;
+    for migration in migrations.iter().filter(|m| m.version > start) {
+        if migration.version != version + 1 {
+            bail!("No migration from version {} to version {}", version, version + 1);
+        }
+
+        let mut tx = db.begin().await?;
+        for statement in migration.statements {
+            sqlx::query(statement)
+                .execute(&mut tx)
+                .await
+                .with_context(|| format!("Migrating to version {}: {}", migration.version, migration.description))?;
+        }
+        sqlx::query("UPDATE settings SET value = ? WHERE key = ?")
+            .bind(migration.version.to_string())
+            .bind(SETTING_VERSION)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        version = migration.version;
+    }
+
+    Ok(())
+}
+
+/// The original (version 1) schema. Later changes are applied by MIGRATIONS.
+async fn create_schema(db: &SqlitePool) -> anyhow::Result<()> {
+    use sqlx::{Executor};
 
     db.execute("CREATE TABLE settings (key TEXT PRIMARY KEY, value TEXT)").await?;
     db.execute("INSERT INTO settings (key,value) VALUES ('version', '1')").await?;
@@ -143,6 +1198,27 @@ pub(crate) async fn create_db(file_name: impl AsRef<Path>) -> anyhow::Result<sql
         )
     ").await?;
 
+    Ok(())
+}
+
+pub(crate) async fn create_db(file_name: impl AsRef<Path>) -> anyhow::Result<sqlx::Pool<sqlx::sqlite::Sqlite>> {
+    if file_name.as_ref().exists() {
+        bail!("Database '{}' already exists", file_name.as_ref().to_string_lossy());
+    }
+    let db = pool(options(file_name).create_if_missing(true));
+
+    create_schema(&db).await?;
+    apply_migrations(&db, MIGRATIONS).await?;
+
+    Ok(db)
+}
 
+/// Like create_db(), but in memory.
+#[cfg(test)]
+pub(crate) async fn create_memory_db() -> anyhow::Result<SqlitePool> {
+    use std::str::FromStr as _;
+    let db = pool(SqliteConnectOptions::from_str("sqlite::memory:")?);
+    create_schema(&db).await?;
+    apply_migrations(&db, MIGRATIONS).await?;
     Ok(db)
 }
\ No newline at end of file
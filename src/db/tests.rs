@@ -0,0 +1,479 @@
+use std::str::FromStr;
+
+use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
+
+use crate::{crypto::{SealedBoxPrivateKey, StreamKey}, server::ReadQuery, tags};
+
+use super::{
+    DB_VERSION, MIGRATIONS, SETTING_API_KEY, SETTING_PENDING_KEY, SETTING_PUBLIC_KEY,
+    Attachment, Draft, Entry, LoginFailure, Migration, VaultExt, apply_migrations, create_db, create_schema, options, pool,
+};
+
+async fn memory_db() -> SqlitePool {
+    let db = pool(SqliteConnectOptions::from_str("sqlite::memory:").unwrap());
+    create_schema(&db).await.unwrap();
+    db
+}
+
+/// An in-memory DB with all migrations applied.
+async fn current_db() -> SqlitePool {
+    let db = memory_db().await;
+    apply_migrations(&db, MIGRATIONS).await.unwrap();
+    db
+}
+
+#[test]
+fn test_db_version_matches_migrations() {
+    let latest = MIGRATIONS.last().map(|m| m.version).unwrap_or(1);
+    assert_eq!(latest, DB_VERSION);
+
+    for (i, migration) in MIGRATIONS.iter().enumerate() {
+        assert_eq!(migration.version, i as u32 + 2);
+    }
+}
+
+#[async_std::test]
+async fn test_apply_migrations() {
+    let db = memory_db().await;
+    let migrations = [
+        Migration{ version: 2, description: "foo", statements: &["CREATE TABLE foo (x INTEGER)"] },
+        Migration{ version: 3, description: "bar", statements: &["ALTER TABLE foo ADD COLUMN y TEXT"] },
+    ];
+
+    apply_migrations(&db, &migrations).await.unwrap();
+    assert_eq!(db.get_version().await.unwrap(), 3);
+    sqlx::query("INSERT INTO foo (x, y) VALUES (1, 'y')").execute(&db).await.unwrap();
+
+    // Already up to date:
+    apply_migrations(&db, &migrations).await.unwrap();
+    assert_eq!(db.get_version().await.unwrap(), 3);
+}
+
+#[async_std::test]
+async fn test_failed_migration_rolls_back() {
+    let db = memory_db().await;
+    let migrations = [
+        Migration{ version: 2, description: "ok", statements: &["CREATE TABLE foo (x INTEGER)"] },
+        Migration{ version: 3, description: "broken", statements: &[
+            "CREATE TABLE bar (x INTEGER)",
+            "NOT VALID SQL",
+        ]},
+    ];
+
+    assert!(apply_migrations(&db, &migrations).await.is_err());
+    assert_eq!(db.get_version().await.unwrap(), 2);
+    assert!(sqlx::query("SELECT * FROM bar").execute(&db).await.is_err());
+}
+
+#[async_std::test]
+async fn test_backup() {
+    let dir = std::env::temp_dir();
+    let file = dir.join(format!("vault-test-{}.sqlite3", std::process::id()));
+    let _ = std::fs::remove_file(&file);
+    let backup = super::backup_path(&file, 1);
+
+    let db = create_db(&file).await.unwrap();
+    db.backup(&backup).await.unwrap();
+    let copy = pool(options(&backup));
+    assert_eq!(copy.get_version().await.unwrap(), DB_VERSION);
+    copy.close().await;
+
+    // Never overwrite an old backup:
+    assert!(db.backup(&backup).await.is_err());
+    // But retrying after a failed upgrade makes another one:
+    let retry = super::backup_path(&file, 1);
+    assert_eq!(retry, dir.join(format!("vault-test-{}.sqlite3.v1.1.bak", std::process::id())));
+    db.backup(&retry).await.unwrap();
+
+    db.close().await;
+    std::fs::remove_file(&file).unwrap();
+    std::fs::remove_file(&backup).unwrap();
+    std::fs::remove_file(&retry).unwrap();
+}
+
+#[async_std::test]
+async fn test_rekey() {
+    let db = current_db().await;
+    let old = SealedBoxPrivateKey::generate();
+    let new = SealedBoxPrivateKey::generate();
+    db.write_setting(SETTING_PUBLIC_KEY, &old.public().to_string()).await.unwrap();
+    db.write_setting(SETTING_PENDING_KEY, "pending").await.unwrap();
+    for (i, text) in ["one", "two"].iter().enumerate() {
+        db.write_entry(Entry{
+            timestamp_ms_utc: i as i64,
+            offset_utc_mins: 0,
+            contents: old.public().encrypt(text.as_bytes()),
+        }).await.unwrap();
+    }
+    db.revise_tagged_entry(0, old.public().encrypt(b"uno"), &[], 10).await.unwrap();
+    db.revise_tagged_entry(1, old.public().encrypt(b"two"), &[old.public().encrypt(b"work")], 10).await.unwrap();
+    db.index_tags(&old).await.unwrap();
+    for entry in db.unindexed_link_entries().await.unwrap() {
+        db.write_links(&entry, &[b"hash".to_vec()]).await.unwrap();
+    }
+
+    assert_eq!(db.rekey(&old, &new, None).await.unwrap(), 2);
+
+    assert!(db.public_key().await.unwrap() == *new.public());
+    assert_eq!(db.read_setting(SETTING_PENDING_KEY).await.unwrap(), None);
+    let texts: Vec<String> = db.all_entries().await.unwrap()
+        .iter()
+        .map(|e| new.decrypt_string(&e.contents).unwrap())
+        .collect();
+    assert_eq!(texts, vec!["uno", "two"]);
+
+    let revisions = db.get_revisions(0).await.unwrap();
+    assert_eq!(new.decrypt_string(&revisions[0].contents).unwrap(), "one");
+
+    // Tag hashes were keyed with the old key:
+    assert_eq!(db.index_tags(&new).await.unwrap(), 1);
+    assert_eq!(new.decrypt_string(&db.tags_between(1, 1).await.unwrap()[0].tag).unwrap(), "work");
+    // So were link hashes:
+    assert!(db.links_to(&[b"hash".to_vec()]).await.unwrap().is_empty());
+    assert_eq!(db.unindexed_link_entries().await.unwrap().len(), 2);
+}
+
+#[async_std::test]
+async fn test_api_token() {
+    let db = current_db().await;
+    let key = SealedBoxPrivateKey::generate();
+    db.write_setting(SETTING_PUBLIC_KEY, &key.public().to_string()).await.unwrap();
+    assert!(db.unlock_api_token(&key.api_token(b"")).await.unwrap().is_none());
+
+    let token = db.enable_api_token(&key).await.unwrap();
+    assert!(db.unlock_api_token(&token).await.unwrap().unwrap().public() == key.public());
+    assert!(db.unlock_api_token("wrong").await.unwrap().is_none());
+    // Enabling it again doesn't change it:
+    assert_eq!(db.enable_api_token(&key).await.unwrap(), token);
+
+    // A revoked token never comes back:
+    db.delete_setting(SETTING_API_KEY).await.unwrap();
+    assert!(db.unlock_api_token(&token).await.unwrap().is_none());
+    let token2 = db.enable_api_token(&key).await.unwrap();
+    assert_ne!(token2, token);
+    assert!(db.unlock_api_token(&token).await.unwrap().is_none());
+
+    // Tokens enabled before they were salted still work:
+    let unsalted = key.api_token(b"");
+    db.write_setting(SETTING_API_KEY, &key.wrap_with_token(&unsalted).unwrap()).await.unwrap();
+    assert!(db.unlock_api_token(&unsalted).await.unwrap().is_some());
+
+    // A new key means a new token:
+    let token = db.enable_api_token(&key).await.unwrap();
+    db.rekey(&key, &SealedBoxPrivateKey::generate(), None).await.unwrap();
+    assert!(db.unlock_api_token(&token).await.unwrap().is_none());
+}
+
+#[async_std::test]
+async fn test_revisions() {
+    let db = current_db().await;
+    let key = SealedBoxPrivateKey::generate();
+    db.write_entry(Entry{
+        timestamp_ms_utc: 100,
+        offset_utc_mins: 0,
+        contents: key.public().encrypt(b"v1"),
+    }).await.unwrap();
+
+    assert!(db.revise_tagged_entry(100, key.public().encrypt(b"v2"), &[], 200).await.unwrap());
+    assert!(db.revise_tagged_entry(100, key.public().encrypt(b"v3"), &[], 300).await.unwrap());
+    // Two saves in the same millisecond:
+    assert!(db.revise_tagged_entry(100, key.public().encrypt(b"v4"), &[], 300).await.unwrap());
+    assert!(!db.revise_tagged_entry(999, key.public().encrypt(b"nope"), &[], 300).await.unwrap());
+
+    let entry = db.get_entry(100).await.unwrap().unwrap();
+    assert_eq!(key.decrypt_string(&entry.contents).unwrap(), "v4");
+
+    let revisions: Vec<(i64, String)> = db.get_revisions(100).await.unwrap()
+        .iter()
+        .map(|r| (r.replaced_ms_utc, key.decrypt_string(&r.contents).unwrap()))
+        .collect();
+    assert_eq!(revisions, vec![(200, "v1".to_string()), (300, "v2".to_string()), (300, "v3".to_string())]);
+    assert_eq!(db.revision_counts(0, 1000).await.unwrap().get(&100), Some(&3));
+
+    assert!(db.delete_entry(100).await.unwrap());
+    assert!(db.get_revisions(100).await.unwrap().is_empty());
+}
+
+#[async_std::test]
+async fn test_tagged_entry_is_atomic() {
+    let db = current_db().await;
+    let key = SealedBoxPrivateKey::generate();
+    let entry = || Entry{
+        timestamp_ms_utc: 1,
+        offset_utc_mins: 0,
+        contents: key.public().encrypt(b"post"),
+    };
+    db.write_full_entry(entry(), &[key.public().encrypt(b"work")], &[]).await.unwrap();
+
+    // A second entry at the same timestamp fails without leaving its tags behind:
+    assert!(db.write_full_entry(entry(), &[key.public().encrypt(b"travel")], &[]).await.is_err());
+    assert!(!db.write_entry_if_new(&entry(), &[key.public().encrypt(b"travel")]).await.unwrap());
+    assert_eq!(db.tags_between(1, 1).await.unwrap().len(), 1);
+
+    // Revising replaces the tags, but not on an entry that's gone:
+    assert!(db.revise_tagged_entry(1, key.public().encrypt(b"v2"), &[key.public().encrypt(b"a"), key.public().encrypt(b"b")], 5).await.unwrap());
+    assert_eq!(db.tags_between(1, 1).await.unwrap().len(), 2);
+    assert_eq!(db.get_revisions(1).await.unwrap().len(), 1);
+    assert!(!db.revise_tagged_entry(2, key.public().encrypt(b"v2"), &[key.public().encrypt(b"a")], 5).await.unwrap());
+    assert!(db.tags_between(2, 2).await.unwrap().is_empty());
+}
+
+#[async_std::test]
+async fn test_links() {
+    let db = current_db().await;
+    let key = SealedBoxPrivateKey::generate();
+    for ts in [1, 2, 3] {
+        db.write_entry(Entry{
+            timestamp_ms_utc: ts,
+            offset_utc_mins: 0,
+            contents: key.public().encrypt(b"post"),
+        }).await.unwrap();
+    }
+    let entries = db.unindexed_link_entries().await.unwrap();
+    assert_eq!(entries.len(), 3);
+    let (a, b) = (vec![b"a".to_vec()], vec![b"b".to_vec()]);
+    assert!(db.write_links(&entries[0], &a).await.unwrap());
+    assert!(db.write_links(&entries[1], &[a[0].clone(), b[0].clone()]).await.unwrap());
+    assert!(db.write_links(&entries[2], &[]).await.unwrap());
+    assert!(db.unindexed_link_entries().await.unwrap().is_empty());
+
+    let sources = |links: Vec<super::Link>| links.iter().map(|l| l.entry_timestamp_ms_utc).collect::<Vec<_>>();
+    assert_eq!(sources(db.links_to(&a).await.unwrap()), vec![1, 2]);
+    assert_eq!(sources(db.links_to(&b).await.unwrap()), vec![2]);
+    assert!(db.links_to(&[]).await.unwrap().is_empty());
+
+    // Editing means indexing again. An index of the old contents is ignored:
+    db.revise_tagged_entry(2, key.public().encrypt(b"edited"), &[], 10).await.unwrap();
+    assert!(!db.write_links(&entries[1], &[]).await.unwrap());
+    let edited = db.unindexed_link_entries().await.unwrap();
+    assert_eq!(sources(db.links_to(&b).await.unwrap()), vec![2]);
+    assert!(db.write_links(&edited[0], &[]).await.unwrap());
+    assert!(db.links_to(&b).await.unwrap().is_empty());
+
+    db.delete_entry(1).await.unwrap();
+    assert!(db.links_to(&a).await.unwrap().is_empty());
+}
+
+#[async_std::test]
+async fn test_tags() {
+    let db = current_db().await;
+    let key = SealedBoxPrivateKey::generate();
+    let hash = |name: &str| key.keyed_hash(tags::HASH_CONTEXT, name.as_bytes());
+    for (ts, names) in [(1, vec!["work"]), (2, vec!["work", "travel"]), (3, vec![])] {
+        let sealed: Vec<Vec<u8>> = names.iter().map(|n| key.public().encrypt(n.as_bytes())).collect();
+        db.write_full_entry(Entry{
+            timestamp_ms_utc: ts,
+            offset_utc_mins: 0,
+            contents: key.public().encrypt(b"post"),
+        }, &sealed, &[]).await.unwrap();
+    }
+
+    // Written without the private key, so nothing matches until indexed:
+    let query = ReadQuery::default();
+    assert!(db.get_posts(&query, Some(&hash("work"))).await.unwrap().is_empty());
+    assert_eq!(db.index_tags(&key).await.unwrap(), 3);
+    assert_eq!(db.index_tags(&key).await.unwrap(), 0);
+
+    let posts: Vec<i64> = db.get_posts(&query, Some(&hash("work"))).await.unwrap()
+        .iter()
+        .map(|e| e.timestamp_ms_utc)
+        .collect();
+    assert_eq!(posts, vec![2, 1]);
+    assert_eq!(db.get_posts(&query, None).await.unwrap().len(), 3);
+
+    let mut counts: Vec<(String, i64)> = db.tag_counts().await.unwrap()
+        .into_iter()
+        .map(|(tag, count)| (key.decrypt_string(&tag).unwrap(), count))
+        .collect();
+    counts.sort();
+    assert_eq!(counts, vec![("travel".to_string(), 1), ("work".to_string(), 2)]);
+
+    // Replacing and deleting:
+    db.revise_tagged_entry(2, key.public().encrypt(b"edited"), &[key.public().encrypt(b"travel")], 10).await.unwrap();
+    assert_eq!(db.tags_between(2, 2).await.unwrap().len(), 1);
+    assert!(db.delete_entry(1).await.unwrap());
+    assert!(db.tags_between(1, 1).await.unwrap().is_empty());
+}
+
+#[async_std::test]
+async fn test_get_posts_paging() {
+    let db = current_db().await;
+    let key = SealedBoxPrivateKey::generate();
+    let day_ms = 24 * 60 * 60 * 1000;
+    for day in 0..5 {
+        db.write_entry(Entry{
+            timestamp_ms_utc: day * day_ms + 1000,
+            offset_utc_mins: 0,
+            contents: key.public().encrypt(b"post"),
+        }).await.unwrap();
+    }
+    let db = &db;
+    assert_eq!(db.neighbors(day_ms + 1000).await.unwrap(), (Some(1000), Some(2 * day_ms + 1000)));
+    assert_eq!(db.neighbors(1000).await.unwrap(), (None, Some(day_ms + 1000)));
+
+    let days = |query: ReadQuery| async move {
+        db.get_posts(&query, None).await.unwrap()
+            .iter()
+            .map(|e| e.timestamp_ms_utc / day_ms)
+            .collect::<Vec<i64>>()
+    };
+    let at = |day: i64| Some(day * day_ms + 1000);
+
+    assert_eq!(days(ReadQuery{ limit: Some(2), ..Default::default() }).await, vec![4, 3]);
+    assert_eq!(days(ReadQuery{ limit: Some(2), before: at(3), ..Default::default() }).await, vec![2, 1]);
+    // Previous page, from the first entry on the page above:
+    assert_eq!(days(ReadQuery{ limit: Some(2), after: at(2), ..Default::default() }).await, vec![4, 3]);
+
+    assert_eq!(days(ReadQuery{ limit: Some(2), chronological: true, ..Default::default() }).await, vec![0, 1]);
+    assert_eq!(days(ReadQuery{ limit: Some(2), chronological: true, after: at(1), ..Default::default() }).await, vec![2, 3]);
+    assert_eq!(days(ReadQuery{ limit: Some(2), chronological: true, before: at(2), ..Default::default() }).await, vec![0, 1]);
+
+    let date = |s: &str| Some(s.parse().unwrap());
+    assert_eq!(days(ReadQuery{ from: date("1970-01-02"), to: date("1970-01-03"), ..Default::default() }).await, vec![2, 1]);
+}
+
+#[async_std::test]
+async fn test_local_dates() {
+    let db = current_db().await;
+    let key = SealedBoxPrivateKey::generate();
+    // 1970-01-02 03:00 UTC, but still Jan 1st in the time zone it was written in:
+    db.write_entry(Entry{
+        timestamp_ms_utc: 27 * 60 * 60 * 1000,
+        offset_utc_mins: -5 * 60,
+        contents: key.public().encrypt(b"post"),
+    }).await.unwrap();
+
+    let on = |day: &str| ReadQuery{ from: Some(day.parse().unwrap()), to: Some(day.parse().unwrap()), ..Default::default() };
+    assert_eq!(db.get_posts(&on("1970-01-01"), None).await.unwrap().len(), 1);
+    assert!(db.get_posts(&on("1970-01-02"), None).await.unwrap().is_empty());
+
+    assert_eq!(db.month_counts().await.unwrap(), vec![("1970-01".to_string(), 1)]);
+    assert_eq!(db.day_counts("1970-01").await.unwrap(), vec![("1970-01-01".to_string(), 1)]);
+    assert!(db.day_counts("1970-02").await.unwrap().is_empty());
+}
+
+#[async_std::test]
+async fn test_entries_on_this_day() {
+    let db = current_db().await;
+    let key = SealedBoxPrivateKey::generate();
+    for date in ["2024-10-16", "2025-10-15", "2025-10-16", "2026-10-16"] {
+        let time = chrono::DateTime::parse_from_rfc3339(&format!("{}T12:00:00+02:00", date)).unwrap();
+        db.write_entry(Entry{
+            timestamp_ms_utc: time.timestamp_millis(),
+            offset_utc_mins: 120,
+            contents: key.public().encrypt(b"post"),
+        }).await.unwrap();
+    }
+
+    let years: Vec<String> = db.entries_on_this_day("2026-10-16".parse().unwrap()).await.unwrap()
+        .iter()
+        .map(|e| e.local_time().format("%F").to_string())
+        .collect();
+    assert_eq!(years, vec!["2025-10-16", "2024-10-16"]);
+}
+
+#[async_std::test]
+async fn test_attachments() {
+    let db = current_db().await;
+    let old = SealedBoxPrivateKey::generate();
+    let new = SealedBoxPrivateKey::generate();
+    db.write_setting(SETTING_PUBLIC_KEY, &old.public().to_string()).await.unwrap();
+    let file_key = StreamKey::generate();
+    let contents = file_key.encrypt(b"photo");
+    db.write_full_entry(Entry{
+        timestamp_ms_utc: 100,
+        offset_utc_mins: 0,
+        contents: old.public().encrypt(b"post"),
+    }, &[], &[Attachment{
+        entry_timestamp_ms_utc: 100,
+        name: old.public().encrypt(b"photo.jpg"),
+        key: old.public().encrypt(file_key.bytes()),
+        contents: contents.clone(),
+    }]).await.unwrap();
+
+    let listed = db.attachments_between(0, 1000).await.unwrap();
+    assert_eq!(listed.len(), 1);
+    let id = listed[0].id;
+    assert_eq!(old.decrypt_string(&listed[0].name).unwrap(), "photo.jpg");
+
+    // Rekeying reseals the file key, but leaves the file alone:
+    db.rekey(&old, &new, None).await.unwrap();
+    let attachment = db.get_attachment(id).await.unwrap().unwrap();
+    assert_eq!(attachment.contents, contents);
+    assert_eq!(new.decrypt_string(&attachment.name).unwrap(), "photo.jpg");
+    let file_key = StreamKey::from_bytes(&new.decrypt(&attachment.key).unwrap()).unwrap();
+    assert_eq!(file_key.decrypt(&attachment.contents).unwrap(), b"photo");
+
+    assert!(db.delete_entry(100).await.unwrap());
+    assert!(db.get_attachment(id).await.unwrap().is_none());
+}
+
+#[async_std::test]
+async fn test_failed_attachment() {
+    let db = current_db().await;
+    let key = SealedBoxPrivateKey::generate();
+    sqlx::query("CREATE TRIGGER full BEFORE INSERT ON attachment BEGIN SELECT RAISE(ABORT, 'disk full'); END")
+        .execute(&db).await.unwrap();
+    let attachment = Attachment{
+        entry_timestamp_ms_utc: 100,
+        name: key.public().encrypt(b"photo.jpg"),
+        key: key.public().encrypt(StreamKey::generate().bytes()),
+        contents: b"photo".to_vec(),
+    };
+    let result = db.write_full_entry(Entry{
+        timestamp_ms_utc: 100,
+        offset_utc_mins: 0,
+        contents: key.public().encrypt(b"post"),
+    }, &[key.public().encrypt(b"work")], &[attachment]).await;
+    assert!(result.is_err());
+
+    // So submitting again doesn't leave a copy of the post without its files:
+    assert!(db.get_entry(100).await.unwrap().is_none());
+    assert!(db.tags_between(100, 100).await.unwrap().is_empty());
+}
+
+#[async_std::test]
+async fn test_drafts() {
+    let db = current_db().await;
+    let old = SealedBoxPrivateKey::generate();
+    let new = SealedBoxPrivateKey::generate();
+    db.write_setting(SETTING_PUBLIC_KEY, &old.public().to_string()).await.unwrap();
+    let draft = |token: &str, saved_ms_utc: i64, text: &str| Draft {
+        token: token.into(),
+        saved_ms_utc,
+        contents: old.public().encrypt(text.as_bytes()),
+        tags: old.public().encrypt(b""),
+    };
+
+    assert!(db.latest_draft().await.unwrap().is_none());
+    db.save_draft(&draft("a", 1, "first")).await.unwrap();
+    db.save_draft(&draft("b", 2, "other")).await.unwrap();
+    db.save_draft(&draft("a", 3, "first, continued")).await.unwrap();
+
+    let latest = db.latest_draft().await.unwrap().unwrap();
+    assert_eq!(latest.token, "a");
+    assert_eq!(old.decrypt_string(&latest.contents).unwrap(), "first, continued");
+
+    db.rekey(&old, &new, None).await.unwrap();
+    let b = db.get_draft("b").await.unwrap().unwrap();
+    assert_eq!(new.decrypt_string(&b.contents).unwrap(), "other");
+
+    db.delete_draft("a").await.unwrap();
+    assert_eq!(db.latest_draft().await.unwrap().unwrap().token, "b");
+}
+
+#[async_std::test]
+async fn test_login_failures() {
+    let db = current_db().await;
+    for (timestamp_ms_utc, remote) in [(1, "192.0.2.1"), (2, "unknown")].iter() {
+        db.record_login_failure(&LoginFailure{
+            timestamp_ms_utc: *timestamp_ms_utc,
+            remote: remote.to_string(),
+            path: "/login".into(),
+        }).await.unwrap();
+    }
+    assert_eq!(db.login_failure_count().await.unwrap(), 2);
+    let recent = db.recent_login_failures(1).await.unwrap();
+    assert_eq!(recent.len(), 1);
+    assert_eq!(recent[0].remote, "unknown");
+}
@@ -0,0 +1,152 @@
+//! Write decrypted entries out of the vault, for backups or moving to another machine.
+
+#[cfg(test)]
+mod tests;
+
+use std::{fs::{self, File, OpenOptions}, io::{BufWriter, Write}, path::Path, str::FromStr};
+
+use anyhow::{Context, bail};
+use chrono::SecondsFormat;
+use serde::{Serialize, Deserialize};
+
+use crate::{crypto::SealedBoxPrivateKey, db::{Entry, VaultExt}, tags};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Format {
+    /// A directory with one Markdown file per entry.
+    Markdown,
+    /// A single JSON array.
+    Json,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" | "md" => Ok(Format::Markdown),
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::JsonLines),
+            _ => bail!("Unknown format '{}'. Expected one of: markdown, json, jsonl", s),
+        }
+    }
+}
+
+/// One decrypted entry, as it appears in an export.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ExportedEntry {
+    pub(crate) timestamp_ms_utc: i64,
+    pub(crate) offset_utc_mins: i32,
+
+    /// The entry's local time, for humans. The fields above are authoritative.
+    #[serde(default)]
+    pub(crate) date: String,
+
+    /// Plaintext. Probably markdown.
+    pub(crate) contents: String,
+
+    /// Tag names, including #hashtags from `contents`.
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+}
+
+/// What `export()` wrote, and what it couldn't.
+pub(crate) struct ExportStats {
+    pub(crate) entries: usize,
+    /// Exports don't include these. They stay in the database.
+    pub(crate) attachments: usize,
+    pub(crate) revisions: i64,
+}
+
+impl ExportedEntry {
+    pub(crate) fn decrypt(entry: &Entry, key: &SealedBoxPrivateKey) -> anyhow::Result<Self> {
+        let contents = key.decrypt_string(&entry.contents)
+            .with_context(|| format!("Decrypting entry {}", entry.timestamp_ms_utc))?;
+        Ok(Self {
+            timestamp_ms_utc: entry.timestamp_ms_utc,
+            offset_utc_mins: entry.offset_utc_mins,
+            date: entry.local_time().to_rfc3339_opts(SecondsFormat::Millis, false),
+            contents,
+            tags: Vec::new(),
+        })
+    }
+
+    /// A Markdown document with the timestamp and tags in YAML-style front matter.
+    pub(crate) fn to_markdown(&self) -> String {
+        let tags = if self.tags.is_empty() {
+            String::new()
+        } else {
+            format!("tags: {}\n", self.tags.join(", "))
+        };
+        format!(
+            "---\ntimestamp_ms_utc: {}\noffset_utc_mins: {}\ndate: {}\n{}---\n{}",
+            self.timestamp_ms_utc,
+            self.offset_utc_mins,
+            self.date,
+            tags,
+            self.contents,
+        )
+    }
+}
+
+/// Named by local time, so a directory listing sorts chronologically. Two entries can have the
+/// same local time, like around a change to daylight saving time, so the UTC timestamp follows.
+fn file_name(entry: &Entry) -> String {
+    format!("{}_{}.md", entry.local_time().format("%Y-%m-%d_%H-%M-%S-%3f"), entry.timestamp_ms_utc)
+}
+
+/// Decrypt every entry in the database, with its tags, and write it to `out`.
+pub(crate) async fn export(db: &sqlx::SqlitePool, key: &SealedBoxPrivateKey, format: Format, out: &Path) -> anyhow::Result<ExportStats> {
+    let entries = db.all_entries().await?;
+    let mut entry_tags = tags::decrypt_tags(db, key, i64::MIN, i64::MAX).await?;
+    let mut decrypt = |entry: &Entry| -> anyhow::Result<ExportedEntry> {
+        Ok(ExportedEntry {
+            tags: entry_tags.remove(&entry.timestamp_ms_utc).unwrap_or_default(),
+            ..ExportedEntry::decrypt(entry, key)?
+        })
+    };
+
+    match format {
+        Format::Markdown => {
+            fs::create_dir_all(out).with_context(|| format!("Creating directory {}", out.to_string_lossy()))?;
+            for entry in &entries {
+                let exported = decrypt(entry)?;
+                let mut file = create_new(&out.join(file_name(entry)))?;
+                file.write_all(exported.to_markdown().as_bytes())?;
+            }
+        },
+        Format::Json => {
+            let exported: anyhow::Result<Vec<ExportedEntry>> = entries.iter()
+                .map(&mut decrypt)
+                .collect();
+            let mut file = BufWriter::new(create_new(out)?);
+            serde_json::to_writer_pretty(&mut file, &exported?)?;
+            file.flush()?;
+        },
+        Format::JsonLines => {
+            let mut file = BufWriter::new(create_new(out)?);
+            for entry in &entries {
+                serde_json::to_writer(&mut file, &decrypt(entry)?)?;
+                file.write_all(b"\n")?;
+            }
+            file.flush()?;
+        },
+    }
+
+    Ok(ExportStats {
+        entries: entries.len(),
+        attachments: db.attachments_between(i64::MIN, i64::MAX).await?.len(),
+        revisions: db.revision_counts(i64::MIN, i64::MAX).await?.values().sum(),
+    })
+}
+
+/// Exports contain plaintext, so never overwrite an existing file.
+fn create_new(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Creating file {}", path.to_string_lossy()))
+}
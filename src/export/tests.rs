@@ -0,0 +1,42 @@
+use crate::{crypto::SealedBoxPrivateKey, db::Entry};
+
+use super::{ExportedEntry, Format, file_name};
+
+#[test]
+fn test_exported_markdown() {
+    let key = SealedBoxPrivateKey::generate();
+    let entry = Entry {
+        timestamp_ms_utc: 1614881730123,
+        offset_utc_mins: -8 * 60,
+        contents: key.public().encrypt(b"Hello, *world*."),
+    };
+
+    let exported = ExportedEntry::decrypt(&entry, &key).unwrap();
+    assert_eq!(exported.date, "2021-03-04T10:15:30.123-08:00");
+    assert_eq!(exported.to_markdown(), "---
+timestamp_ms_utc: 1614881730123
+offset_utc_mins: -480
+date: 2021-03-04T10:15:30.123-08:00
+---
+Hello, *world*.");
+    assert_eq!(file_name(&entry), "2021-03-04_10-15-30-123_1614881730123.md");
+
+    // An hour later in UTC, but the same local time, after the clocks went back:
+    let after_dst = Entry {
+        timestamp_ms_utc: entry.timestamp_ms_utc + 60 * 60 * 1000,
+        offset_utc_mins: -9 * 60,
+        contents: Vec::new(),
+    };
+    assert_eq!(after_dst.local_time().naive_local(), entry.local_time().naive_local());
+    assert_ne!(file_name(&after_dst), file_name(&entry));
+
+    let tagged = ExportedEntry { tags: vec!["home".into(), "work".into()], ..exported };
+    assert!(tagged.to_markdown().contains("\ntags: home, work\n---\n"));
+}
+
+#[test]
+fn test_parse_format() {
+    assert_eq!("markdown".parse::<Format>().unwrap(), Format::Markdown);
+    assert_eq!("jsonl".parse::<Format>().unwrap(), Format::JsonLines);
+    assert!("xml".parse::<Format>().is_err());
+}
@@ -0,0 +1,249 @@
+//! Read entries into the vault from exports or another vault database.
+
+#[cfg(test)]
+mod tests;
+
+use std::{fs, path::{Path, PathBuf}, str::FromStr};
+
+use anyhow::{Context, bail, format_err};
+use chrono::DateTime;
+
+use crate::{
+    crypto::{SealedBoxPrivateKey, SealedBoxPublicKey},
+    db::{self, Entry, VaultExt},
+    export::{self, ExportedEntry},
+    tags,
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Format {
+    /// Anything `vault export` can write.
+    Export(export::Format),
+    /// Another vault database, including ones from the older Deno version of vault.
+    Vault,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vault" => Ok(Format::Vault),
+            _ => s.parse().map(Format::Export).map_err(|_| {
+                format_err!("Unknown format '{}'. Expected one of: markdown, json, jsonl, vault", s)
+            }),
+        }
+    }
+}
+
+impl Format {
+    /// Guess the format from a directory or file extension.
+    pub(crate) fn guess(path: &Path) -> anyhow::Result<Self> {
+        if path.is_dir() {
+            return Ok(Format::Export(export::Format::Markdown));
+        }
+        let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+        match ext.as_str() {
+            "md" | "markdown" => Ok(Format::Export(export::Format::Markdown)),
+            "json" => Ok(Format::Export(export::Format::Json)),
+            "jsonl" => Ok(Format::Export(export::Format::JsonLines)),
+            "db" | "sqlite" | "sqlite3" => Ok(Format::Vault),
+            _ => bail!("Can't guess the format of '{}'. Use --format.", path.to_string_lossy()),
+        }
+    }
+}
+
+/// What to do when an imported entry has the same timestamp as an existing one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum OnConflict {
+    /// Assume it's the same entry, imported before.
+    Skip,
+    /// Move the imported entry forward by a millisecond until it fits.
+    Shift,
+}
+
+impl FromStr for OnConflict {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnConflict::Skip),
+            "shift" => Ok(OnConflict::Shift),
+            _ => bail!("Unknown conflict option '{}'. Expected one of: skip, shift", s),
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub(crate) struct ImportStats {
+    pub(crate) imported: usize,
+    pub(crate) skipped: usize,
+    pub(crate) shifted: usize,
+}
+
+/// Read plaintext entries from exported files.
+pub(crate) fn read_export(path: &Path, format: export::Format) -> anyhow::Result<Vec<ExportedEntry>> {
+    match format {
+        export::Format::Markdown => {
+            let files: Vec<PathBuf> = if path.is_dir() {
+                let mut files = Vec::new();
+                for dir_entry in fs::read_dir(path)? {
+                    let file = dir_entry?.path();
+                    if file.extension().map(|e| e == "md").unwrap_or(false) {
+                        files.push(file);
+                    }
+                }
+                files.sort();
+                files
+            } else {
+                vec![path.to_owned()]
+            };
+
+            files.iter().map(|file| {
+                let text = fs::read_to_string(file)?;
+                parse_markdown(&text).with_context(|| format!("Reading {}", file.to_string_lossy()))
+            }).collect()
+        },
+        export::Format::Json => {
+            let text = fs::read_to_string(path)?;
+            Ok(serde_json::from_str(&text)?)
+        },
+        export::Format::JsonLines => {
+            let text = fs::read_to_string(path)?;
+            text.lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(i, line)| serde_json::from_str(line).with_context(|| format!("Reading line {}", i + 1)))
+                .collect()
+        },
+    }
+}
+
+/// Parse a Markdown file as written by `vault export`.
+/// Needs front matter with `timestamp_ms_utc` and `offset_utc_mins`, or an RFC 3339 `date`.
+pub(crate) fn parse_markdown(text: &str) -> anyhow::Result<ExportedEntry> {
+    let rest = text.strip_prefix("---\n")
+        .or_else(|| text.strip_prefix("---\r\n"))
+        .ok_or_else(|| format_err!("Missing front matter"))?;
+
+    let mut timestamp_ms_utc = None;
+    let mut offset_utc_mins = None;
+    let mut date = String::new();
+    let mut entry_tags = Vec::new();
+
+    let mut offset = 0;
+    let mut closed = false;
+    for line in rest.split_inclusive('\n') {
+        offset += line.len();
+        let line = line.trim_end();
+        if line == "---" {
+            closed = true;
+            break;
+        }
+        let (key, value) = match line.split_once(':') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => continue,
+        };
+        match key {
+            "timestamp_ms_utc" => timestamp_ms_utc = Some(value.parse().context("Parsing timestamp_ms_utc")?),
+            "offset_utc_mins" => offset_utc_mins = Some(value.parse().context("Parsing offset_utc_mins")?),
+            "date" => date = value.to_string(),
+            "tags" => entry_tags = tags::parse_tag_field(value).into_iter().collect(),
+            _ => {},
+        }
+    }
+    if !closed {
+        bail!("Front matter is missing its closing '---'");
+    }
+
+    let (timestamp_ms_utc, offset_utc_mins) = match (timestamp_ms_utc, offset_utc_mins) {
+        (Some(t), Some(o)) => (t, o),
+        _ => {
+            let parsed = DateTime::parse_from_rfc3339(&date)
+                .context("Front matter needs timestamp_ms_utc and offset_utc_mins, or an RFC 3339 date")?;
+            (
+                timestamp_ms_utc.unwrap_or_else(|| parsed.timestamp_millis()),
+                offset_utc_mins.unwrap_or_else(|| parsed.offset().local_minus_utc() / 60),
+            )
+        },
+    };
+
+    Ok(ExportedEntry {
+        timestamp_ms_utc,
+        offset_utc_mins,
+        date,
+        contents: rest[offset..].to_string(),
+        tags: entry_tags,
+    })
+}
+
+/// Read and decrypt all entries from another vault database.
+pub(crate) async fn read_vault(file: &Path, secret: &str) -> anyhow::Result<Vec<ExportedEntry>> {
+    let other = db::pool(db::options(file));
+    let key = match other.unlock(secret).await? {
+        Some(key) => key,
+        None => {
+            // The Deno version of vault handed out the seed instead of the private key.
+            let key = SealedBoxPrivateKey::from_base58_seed(secret)
+                .map_err(|_| format_err!("Incorrect private key for {}", file.to_string_lossy()))?;
+            if key.public() != &other.public_key().await? {
+                bail!("Incorrect private key for {}", file.to_string_lossy());
+            }
+            key
+        },
+    };
+
+    // Tags arrived in version 3. The Deno version doesn't have them.
+    let mut entry_tags = if other.get_version().await? >= 3 {
+        tags::decrypt_tags(&other, &key, i64::MIN, i64::MAX).await?
+    } else {
+        Default::default()
+    };
+    let entries: anyhow::Result<Vec<ExportedEntry>> = other.all_entries().await?
+        .iter()
+        .map(|e| Ok(ExportedEntry {
+            tags: entry_tags.remove(&e.timestamp_ms_utc).unwrap_or_default(),
+            ..ExportedEntry::decrypt(e, &key)?
+        }))
+        .collect();
+    other.close().await;
+    entries
+}
+
+/// Encrypt entries and their tags with the vault's public key and save them.
+pub(crate) async fn import(db: &sqlx::SqlitePool, entries: Vec<ExportedEntry>, on_conflict: OnConflict) -> anyhow::Result<ImportStats> {
+    let public_key: SealedBoxPublicKey = db.public_key().await?;
+    let mut stats = ImportStats::default();
+
+    for exported in entries {
+        let mut entry = Entry {
+            timestamp_ms_utc: exported.timestamp_ms_utc,
+            offset_utc_mins: exported.offset_utc_mins,
+            contents: public_key.encrypt(exported.contents.as_bytes()),
+        };
+
+        let sealed: Vec<Vec<u8>> = exported.tags.iter()
+            .map(|name| public_key.encrypt(tags::normalize(name).as_bytes()))
+            .collect();
+
+        loop {
+            let timestamp = entry.timestamp_ms_utc;
+            if db.write_entry_if_new(&entry, &sealed).await? {
+                if timestamp != exported.timestamp_ms_utc {
+                    stats.shifted += 1;
+                }
+                stats.imported += 1;
+                break;
+            }
+            match on_conflict {
+                OnConflict::Skip => {
+                    stats.skipped += 1;
+                    break;
+                },
+                OnConflict::Shift => entry.timestamp_ms_utc += 1,
+            }
+        }
+    }
+
+    Ok(stats)
+}
@@ -0,0 +1,172 @@
+use std::path::PathBuf;
+
+use sqlx::SqlitePool;
+
+use super::{Format, OnConflict, import, parse_markdown, read_vault};
+use crate::{
+    crypto::SealedBoxPrivateKey,
+    db::{self, Entry, VaultExt as _},
+    export::{self, ExportedEntry},
+    tags,
+};
+
+#[test]
+fn test_parse_markdown() {
+    let entry = parse_markdown("---
+timestamp_ms_utc: 1614881730123
+offset_utc_mins: -480
+date: 2021-03-04T10:15:30.123-08:00
+tags: work, #Home
+---
+# Hello
+
+---
+
+World").unwrap();
+
+    assert_eq!(entry.timestamp_ms_utc, 1614881730123);
+    assert_eq!(entry.offset_utc_mins, -480);
+    assert_eq!(entry.contents, "# Hello\n\n---\n\nWorld");
+    assert_eq!(entry.tags, vec!["home", "work"]);
+}
+
+#[test]
+fn test_parse_markdown_date_only() {
+    let entry = parse_markdown("---\r\ndate: 2021-03-04T10:15:30.123-08:00\r\n---\r\nHi").unwrap();
+    assert_eq!(entry.timestamp_ms_utc, 1614881730123);
+    assert_eq!(entry.offset_utc_mins, -480);
+    assert_eq!(entry.contents, "Hi");
+
+    assert!(parse_markdown("No front matter").is_err());
+    assert!(parse_markdown("---\ntitle: no date\n---\nHi").is_err());
+}
+
+#[test]
+fn test_parse_format() {
+    assert_eq!("vault".parse::<Format>().unwrap(), Format::Vault);
+    assert_eq!("json".parse::<Format>().unwrap(), Format::Export(export::Format::Json));
+    assert!("xml".parse::<Format>().is_err());
+}
+
+/// A new vault file with `key`, in the temp directory.
+async fn temp_vault(name: &str, key: &SealedBoxPrivateKey) -> (PathBuf, SqlitePool) {
+    let file = std::env::temp_dir().join(format!("vault-import-test-{}-{}.sqlite3", std::process::id(), name));
+    let _ = std::fs::remove_file(&file);
+    let db = db::create_db(&file).await.unwrap();
+    db.write_setting(db::SETTING_PUBLIC_KEY, &key.public().to_string()).await.unwrap();
+    (file, db)
+}
+
+async fn remove_vault(file: PathBuf, db: SqlitePool) {
+    db.close().await;
+    let _ = std::fs::remove_file(&file);
+}
+
+fn exported(timestamp_ms_utc: i64, contents: &str, tags: &[&str]) -> ExportedEntry {
+    ExportedEntry {
+        timestamp_ms_utc,
+        offset_utc_mins: -480,
+        date: String::new(),
+        contents: contents.into(),
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+    }
+}
+
+/// Each entry's timestamp, plaintext, and tags.
+async fn contents(db: &SqlitePool, key: &SealedBoxPrivateKey) -> Vec<(i64, String, Vec<String>)> {
+    let mut entry_tags = tags::decrypt_tags(db, key, i64::MIN, i64::MAX).await.unwrap();
+    db.all_entries().await.unwrap().iter()
+        .map(|e| (
+            e.timestamp_ms_utc,
+            key.decrypt_string(&e.contents).unwrap(),
+            entry_tags.remove(&e.timestamp_ms_utc).unwrap_or_default(),
+        ))
+        .collect()
+}
+
+#[async_std::test]
+async fn test_import_conflicts() {
+    let key = SealedBoxPrivateKey::generate();
+    let (file, db) = temp_vault("conflicts", &key).await;
+
+    let stats = import(&db, vec![exported(1000, "one", &["Work"])], OnConflict::Skip).await.unwrap();
+    assert_eq!((stats.imported, stats.skipped, stats.shifted), (1, 0, 0));
+
+    // Importing the same export again:
+    let stats = import(&db, vec![exported(1000, "one", &["Work"])], OnConflict::Skip).await.unwrap();
+    assert_eq!((stats.imported, stats.skipped, stats.shifted), (0, 1, 0));
+
+    // Different entries at the same time move forward until they fit:
+    let entries = vec![exported(1000, "two", &[]), exported(1000, "three", &["home"])];
+    let stats = import(&db, entries, OnConflict::Shift).await.unwrap();
+    assert_eq!((stats.imported, stats.skipped, stats.shifted), (2, 0, 2));
+
+    assert_eq!(contents(&db, &key).await, vec![
+        (1000, "one".to_string(), vec!["work".to_string()]),
+        (1001, "two".to_string(), vec![]),
+        (1002, "three".to_string(), vec!["home".to_string()]),
+    ]);
+    assert_eq!(db.get_entry(1001).await.unwrap().unwrap().offset_utc_mins, -480);
+    remove_vault(file, db).await;
+}
+
+#[async_std::test]
+async fn test_read_vault() {
+    let key = SealedBoxPrivateKey::generate();
+    let (file, other) = temp_vault("read", &key).await;
+    other.write_full_entry(Entry{
+        timestamp_ms_utc: 1000,
+        offset_utc_mins: 60,
+        contents: key.public().encrypt(b"tagged"),
+    }, &[key.public().encrypt(b"work"), key.public().encrypt(b"travel")], &[]).await.unwrap();
+    other.write_entry(Entry{
+        timestamp_ms_utc: 2000,
+        offset_utc_mins: 60,
+        contents: key.public().encrypt(b"untagged"),
+    }).await.unwrap();
+
+    assert!(read_vault(&file, &SealedBoxPrivateKey::generate().to_string()).await.is_err());
+    let entries = read_vault(&file, &key.to_string()).await.unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!((entries[0].timestamp_ms_utc, entries[0].offset_utc_mins), (1000, 60));
+    assert_eq!(entries[0].contents, "tagged");
+    assert_eq!(entries[0].tags, vec!["travel", "work"]);
+    assert!(entries[1].tags.is_empty());
+
+    // Tags come along into the new vault:
+    let new_key = SealedBoxPrivateKey::generate();
+    let (new_file, db) = temp_vault("read-target", &new_key).await;
+    import(&db, entries, OnConflict::Skip).await.unwrap();
+    assert_eq!(contents(&db, &new_key).await, vec![
+        (1000, "tagged".to_string(), vec!["travel".to_string(), "work".to_string()]),
+        (2000, "untagged".to_string(), vec![]),
+    ]);
+    remove_vault(new_file, db).await;
+
+    // Before version 3, there were no tags:
+    sqlx::query("DROP TABLE entry_tag").execute(&other).await.unwrap();
+    other.write_setting(db::SETTING_VERSION, "2").await.unwrap();
+    let entries = read_vault(&file, &key.to_string()).await.unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries.iter().all(|e| e.tags.is_empty()));
+    remove_vault(file, other).await;
+}
+
+#[async_std::test]
+async fn test_read_vault_seed() {
+    // The Deno version handed out this seed, rather than the private key made from it:
+    let seed = bs58::encode(sodiumoxide::randombytes::randombytes(32)).into_string();
+    let key = SealedBoxPrivateKey::from_base58_seed(&seed).unwrap();
+    let (file, other) = temp_vault("seed", &key).await;
+    other.write_entry(Entry{
+        timestamp_ms_utc: 1000,
+        offset_utc_mins: 0,
+        contents: key.public().encrypt(b"from deno"),
+    }).await.unwrap();
+
+    let entries = read_vault(&file, &seed).await.unwrap();
+    assert_eq!(entries[0].contents, "from deno");
+    let other_seed = bs58::encode(sodiumoxide::randombytes::randombytes(32)).into_string();
+    assert!(read_vault(&file, &other_seed).await.is_err());
+    remove_vault(file, other).await;
+}
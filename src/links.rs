@@ -0,0 +1,165 @@
+//! Wiki-style links between entries, like `[[2021-03-04]]` or `[[entry:1614816000000|that day]]`.
+//!
+//! Links are only found in decrypted markdown. So for backlinks, each entry's link targets are
+//! hashed with a key derived from the private key, like tags, and kept in the entry_link table.
+//! Entries can be written without logging in, so they're indexed the next time backlinks are needed.
+
+#[cfg(test)]
+mod tests;
+
+use std::{cell::RefCell, collections::BTreeSet};
+
+use chrono::NaiveDate;
+use comrak::{
+    Arena, ComrakOptions, arena_tree::Node, format_html, parse_document,
+    nodes::{Ast, AstNode, NodeLink, NodeValue},
+};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::{crypto::SealedBoxPrivateKey, db::VaultExt};
+
+/// The `context` for SealedBoxPrivateKey::keyed_hash() of link targets.
+pub(crate) const HASH_CONTEXT: &str = "vault.link";
+
+/// What a wiki link points to.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum Target {
+    /// All entries written on a day.
+    Day(NaiveDate),
+    /// One entry, by timestamp_ms_utc.
+    Entry(i64),
+}
+
+impl Target {
+    fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if let Some(timestamp) = text.strip_prefix("entry:") {
+            return timestamp.trim().parse().ok().map(Target::Entry);
+        }
+        NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().map(Target::Day)
+    }
+
+    /// For looking up links to this target, without revealing it. See VaultExt::links_to().
+    pub(crate) fn hash(&self, key: &SealedBoxPrivateKey) -> Vec<u8> {
+        let name = match self {
+            Target::Day(day) => day.format("%Y-%m-%d").to_string(),
+            Target::Entry(timestamp) => format!("entry:{}", timestamp),
+        };
+        key.keyed_hash(HASH_CONTEXT, name.as_bytes())
+    }
+
+    pub(crate) fn url(&self) -> String {
+        match self {
+            Target::Day(day) => format!("/read?from={day}&to={day}&chronological=true", day=day),
+            Target::Entry(timestamp) => format!("/entry/{}", timestamp),
+        }
+    }
+}
+
+static LINK: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\[\[([^\[\]|]+)(?:\|([^\[\]]+))?\]\]").expect("Valid regex")
+});
+
+/// Everything a markdown document links to, in order. Ignores links in code.
+pub(crate) fn targets(markdown: &str, options: &ComrakOptions) -> Vec<Target> {
+    let arena = Arena::new();
+    let root = parse_document(&arena, markdown, options);
+
+    let mut targets = Vec::new();
+    for node in linkable_text(root) {
+        if let NodeValue::Text(ref text) = node.data.borrow().value {
+            let text = String::from_utf8_lossy(text);
+            targets.extend(LINK.captures_iter(&text).filter_map(|c| Target::parse(&c[1])));
+        }
+    }
+    targets
+}
+
+/// Index the links in entries that were written or edited since the last time.
+/// Returns how many entries were indexed.
+pub(crate) async fn index_links(db: &sqlx::SqlitePool, key: &SealedBoxPrivateKey, options: &ComrakOptions) -> anyhow::Result<usize> {
+    let mut indexed = 0;
+    for entry in db.unindexed_link_entries().await? {
+        let markdown = key.decrypt_string(&entry.contents)?;
+        let hashes: BTreeSet<Vec<u8>> = targets(&markdown, options).iter()
+            .map(|target| target.hash(key))
+            .collect();
+        let hashes: Vec<Vec<u8>> = hashes.into_iter().collect();
+        // If it was edited meanwhile, it'll be indexed next time.
+        if db.write_links(&entry, &hashes).await? {
+            indexed += 1;
+        }
+    }
+    Ok(indexed)
+}
+
+/// Render markdown to HTML, with wiki links turned into links.
+pub(crate) fn render_markdown(markdown: &str, options: &ComrakOptions) -> String {
+    let arena = Arena::new();
+    let root = parse_document(&arena, markdown, options);
+
+    for node in linkable_text(root) {
+        let text = match node.data.borrow().value {
+            NodeValue::Text(ref text) => String::from_utf8_lossy(text).into_owned(),
+            _ => continue,
+        };
+
+        let mut end = 0;
+        for captures in LINK.captures_iter(&text) {
+            let whole = captures.get(0).expect("Capture 0 is the whole match");
+            let target = match Target::parse(&captures[1]) {
+                Some(target) => target,
+                None => continue,
+            };
+            let label = captures.get(2).unwrap_or_else(|| captures.get(1).expect("Required group")).as_str();
+
+            node.insert_before(new_node(&arena, NodeValue::Text(text[end..whole.start()].into())));
+            let link = new_node(&arena, NodeValue::Link(NodeLink {
+                url: target.url().into_bytes(),
+                title: Vec::new(),
+            }));
+            link.append(new_node(&arena, NodeValue::Text(label.trim().into())));
+            node.insert_before(link);
+            end = whole.end();
+        }
+        if end > 0 {
+            node.insert_before(new_node(&arena, NodeValue::Text(text[end..].into())));
+            node.detach();
+        }
+    }
+
+    let mut html = Vec::new();
+    format_html(root, options, &mut html).expect("Writing to a Vec shouldn't fail");
+    String::from_utf8_lossy(&html).into_owned()
+}
+
+/// Text nodes that could contain a wiki link. The parser splits text at brackets,
+/// so adjacent text nodes are merged first.
+fn linkable_text<'a>(root: &'a AstNode<'a>) -> Vec<&'a AstNode<'a>> {
+    let mut nodes = Vec::new();
+    for node in root.descendants() {
+        if !is_text(node) || node.previous_sibling().map(is_text).unwrap_or(false) {
+            continue;
+        }
+        if node.ancestors().any(|n| matches!(n.data.borrow().value, NodeValue::Link(_) | NodeValue::Image(_))) {
+            continue;
+        }
+        while let Some(next) = node.next_sibling().filter(|n| is_text(n)) {
+            if let (NodeValue::Text(ref mut text), NodeValue::Text(ref more)) = (&mut node.data.borrow_mut().value, &next.data.borrow().value) {
+                text.extend_from_slice(more);
+            }
+            next.detach();
+        }
+        nodes.push(node);
+    }
+    nodes
+}
+
+fn is_text<'a>(node: &'a AstNode<'a>) -> bool {
+    matches!(node.data.borrow().value, NodeValue::Text(_))
+}
+
+fn new_node<'a>(arena: &'a Arena<AstNode<'a>>, value: NodeValue) -> &'a AstNode<'a> {
+    arena.alloc(Node::new(RefCell::new(Ast::new(value))))
+}
@@ -0,0 +1,28 @@
+use comrak::ComrakOptions;
+
+use super::{Target, render_markdown, targets};
+
+#[test]
+fn test_targets() {
+    let options = ComrakOptions::default();
+    let markdown = "See [[2021-03-04]] and [[entry:1614816000000|that post]].\n\n`[[2020-01-01]]` [[nope]]";
+    assert_eq!(targets(markdown, &options), vec![
+        Target::Day("2021-03-04".parse().unwrap()),
+        Target::Entry(1614816000000),
+    ]);
+}
+
+#[test]
+fn test_render_markdown() {
+    let options = ComrakOptions::default();
+    assert_eq!(
+        render_markdown("See [[2021-03-04]], *and* [[entry:5|this & that]]. [[nope]]", &options),
+        "<p>See <a href=\"/read?from=2021-03-04&amp;to=2021-03-04&amp;chronological=true\">2021-03-04</a>, \
+        <em>and</em> <a href=\"/entry/5\">this &amp; that</a>. [[nope]]</p>\n",
+    );
+    // Links aren't nested:
+    assert_eq!(
+        render_markdown("[a [[2021-03-04]]](/x)", &options),
+        "<p><a href=\"/x\">a [[2021-03-04]]</a></p>\n",
+    );
+}
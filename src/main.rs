@@ -1,13 +1,21 @@
 
 
+mod archive;
 mod crypto;
 mod db;
+mod export;
+mod import;
+mod links;
+mod search;
+mod tags;
 mod statics;
 mod server;
 
-use std::{path::PathBuf};
+use std::{net::IpAddr, path::{Path, PathBuf}};
 
+use anyhow::Context as _;
 use async_std::task::block_on;
+use chrono::TimeZone as _;
 use structopt::StructOpt;
 
 use db::VaultExt as _;
@@ -31,8 +39,13 @@ enum MainCommands {
     Open(OpenCommand),
     Serve(ServeCommand),
     Init(InitCommand),
-    // #[structopt(setting(structopt::clap::AppSettings::Hidden))] // Not yet implemented.
-    // Upgrade(UpgradeCommand),
+    Upgrade(UpgradeCommand),
+    Passphrase(PassphraseCommand),
+    Export(ExportCommand),
+    Import(ImportCommand),
+    Rekey(RekeyCommand),
+    ApiToken(ApiTokenCommand),
+    Access(AccessCommand),
 }
 
 #[derive(StructOpt, Clone)]
@@ -51,8 +64,48 @@ struct OpenOpts {
     #[structopt(long)]
     no_browser: bool,
 
+    /// The address to listen on. Anything but loopback needs TLS, or --insecure-bind.
+    #[structopt(long, default_value="127.0.0.1")]
+    bind: IpAddr,
+
     #[structopt(long, default_value="8080")]
     port: u16,
+
+    /// A PEM certificate chain, to serve HTTPS.
+    #[structopt(long, parse(from_os_str), requires="tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// The PEM private key for --tls-cert.
+    #[structopt(long, parse(from_os_str), requires="tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Listen on a Unix domain socket instead of TCP, e.g. behind a reverse proxy.
+    #[structopt(long, parse(from_os_str))]
+    unix_socket: Option<PathBuf>,
+
+    /// Allow a non-loopback --bind without TLS. Only do this if something else provides TLS.
+    #[structopt(long)]
+    insecure_bind: bool,
+
+    /// Keep logins across restarts, with the cookie key saved in this file.
+    /// It's encrypted with a key in the vault, so it's only useful alongside this vault.
+    /// But the vault and this file together can decrypt old login cookies, which hold the private
+    /// key. So keep them apart, and treat backups that have both as carefully as the key itself.
+    #[structopt(long, parse(from_os_str))]
+    session_key_file: Option<PathBuf>,
+
+    /// Log out this long after logging in, however active the session.
+    #[structopt(long, default_value="12")]
+    session_hours: u64,
+
+    /// Log out after this long without a request.
+    #[structopt(long, default_value="30")]
+    idle_minutes: u64,
+
+    /// Encrypt and decrypt in the browser, so the server never sees plaintext or your private key.
+    /// Only writing and reading are available in this mode.
+    #[structopt(long)]
+    client_side: bool,
 }
 
 impl OpenCommand {
@@ -80,48 +133,409 @@ impl ServeCommand {
 
 #[derive(StructOpt)]
 #[structopt(about = "Initialize a new database file")]
-
 struct InitCommand { 
     #[structopt(parse(from_os_str))]
     sqlite_file: PathBuf,
+
+    /// Also set a passphrase that can be used in place of the private key.
+    #[structopt(long)]
+    passphrase: bool,
 }
 
 impl InitCommand {
     fn run(&self, _opts: &VaultOpts) -> anyhow::Result<()> {
+        let passphrase = if self.passphrase { Some(prompt_new_passphrase()?) } else { None };
+
         let db = block_on(db::create_db(&self.sqlite_file))?;
 
         let secret = crypto::SealedBoxPrivateKey::generate();
         let pub_key = secret.public().to_string();
         block_on(db.write_setting(db::SETTING_PUBLIC_KEY, &pub_key))?;
+        if let Some(passphrase) = passphrase {
+            block_on(db.write_setting(db::SETTING_WRAPPED_KEY, &secret.wrap(&passphrase)?))?;
+        }
         block_on(db.close());
         println!("OK. Database initialized.");
         println!("Your PRIVATE KEY (password) is: {}", secret);
+        if self.passphrase {
+            println!("You can log in with your passphrase, but the private key is your only backup if you forget it.");
+        }
         println!("You must save this. There is no way to recover or reset it.");
 
         Ok(())
     }
 }
 
-// #[derive(StructOpt)]
-// #[structopt(about = "Upgrade database schema to a new version")]
-// struct UpgradeCommand {
-//     #[structopt(parse(from_os_str))]
-//     sqlite_file: PathBuf,
-// }
+#[derive(StructOpt)]
+#[structopt(about = "Set or change the passphrase that unlocks your private key")]
+struct PassphraseCommand {
+    #[structopt(parse(from_os_str))]
+    sqlite_file: PathBuf,
+}
+
+impl PassphraseCommand {
+    fn run(&self, _opts: &VaultOpts) -> anyhow::Result<()> {
+        block_on(self.async_run())
+    }
+
+    async fn async_run(&self) -> anyhow::Result<()> {
+        let db = open_db(&self.sqlite_file).await?;
+        let secret = prompt_private_key(&db).await?;
+        let passphrase = prompt_new_passphrase()?;
+
+        db.write_setting(db::SETTING_WRAPPED_KEY, &secret.wrap(&passphrase)?).await?;
+        db.close().await;
+        println!("OK. Passphrase saved.");
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Decrypt all entries and write them to files")]
+struct ExportCommand {
+    #[structopt(parse(from_os_str))]
+    sqlite_file: PathBuf,
+
+    /// A directory for the markdown format, or a file for json and jsonl.
+    #[structopt(parse(from_os_str))]
+    out: PathBuf,
+
+    /// One of: markdown, json, jsonl
+    #[structopt(long, default_value="markdown")]
+    format: export::Format,
+}
+
+impl ExportCommand {
+    fn run(&self, _opts: &VaultOpts) -> anyhow::Result<()> {
+        block_on(self.async_run())
+    }
+
+    async fn async_run(&self) -> anyhow::Result<()> {
+        let db = open_db(&self.sqlite_file).await?;
+        let secret = prompt_private_key(&db).await?;
+
+        let stats = export::export(&db, &secret, self.format, &self.out).await?;
+        db.close().await;
+        println!("OK. Exported {} entries to: {}", stats.entries, self.out.to_string_lossy());
+        println!("These files are NOT encrypted. Store them somewhere safe.");
+        if stats.attachments > 0 || stats.revisions > 0 {
+            println!(
+                "Not exported: {} attachments and {} earlier versions of edited entries. Keep a copy of the database for those.",
+                stats.attachments, stats.revisions,
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Encrypt and save entries from an export or another vault database")]
+struct ImportCommand {
+    #[structopt(parse(from_os_str))]
+    sqlite_file: PathBuf,
+
+    /// A directory of markdown files, a single file, or another vault database.
+    #[structopt(parse(from_os_str))]
+    source: PathBuf,
+
+    /// One of: markdown, json, jsonl, vault. Guessed from the source if not given.
+    #[structopt(long)]
+    format: Option<import::Format>,
+
+    /// What to do with entries that have the same timestamp as an existing entry.
+    /// One of: skip, shift
+    #[structopt(long, default_value="skip")]
+    on_conflict: import::OnConflict,
+}
+
+impl ImportCommand {
+    fn run(&self, _opts: &VaultOpts) -> anyhow::Result<()> {
+        block_on(self.async_run())
+    }
+
+    async fn async_run(&self) -> anyhow::Result<()> {
+        let db = open_db(&self.sqlite_file).await?;
+        let format = match self.format {
+            Some(f) => f,
+            None => import::Format::guess(&self.source)?,
+        };
+
+        let entries = match format {
+            import::Format::Export(format) => import::read_export(&self.source, format)?,
+            import::Format::Vault => {
+                let prompt = format!("Private key or seed for {}: ", self.source.to_string_lossy());
+                let secret = rpassword::prompt_password_stderr(&prompt)?;
+                import::read_vault(&self.source, &secret).await?
+            },
+        };
+
+        let stats = import::import(&db, entries, self.on_conflict).await?;
+        db.close().await;
+
+        println!("OK. Imported {} entries.", stats.imported);
+        if stats.shifted > 0 {
+            println!("Moved {} entries forward by a few milliseconds to avoid existing entries.", stats.shifted);
+        }
+        if stats.skipped > 0 {
+            println!("Skipped {} entries with the same timestamp as an existing entry.", stats.skipped);
+        }
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Re-encrypt all entries with a new private key. Stop any running server first.")]
+struct RekeyCommand {
+    #[structopt(parse(from_os_str))]
+    sqlite_file: PathBuf,
+}
+
+impl RekeyCommand {
+    fn run(&self, _opts: &VaultOpts) -> anyhow::Result<()> {
+        block_on(self.async_run())
+    }
+
+    async fn async_run(&self) -> anyhow::Result<()> {
+        let db = open_db(&self.sqlite_file).await?;
+        let secret = rpassword::prompt_password_stderr("Current private key or passphrase: ")?;
+        let old_key = db.unlock(&secret).await?
+            .ok_or_else(|| anyhow::format_err!("Incorrect private key or passphrase"))?;
+
+        // Save the new key (sealed to the old one) before changing anything,
+        // so that an interrupted rekey can be resumed with the same key.
+        let new_key = match db.read_setting(db::SETTING_PENDING_KEY).await? {
+            Some(pending) => {
+                println!("Resuming an interrupted rekey.");
+                let sealed = bs58::decode(pending).into_vec()?;
+                crypto::SealedBoxPrivateKey::from_bytes(&old_key.decrypt(&sealed)?)?
+            },
+            None => {
+                let new_key = crypto::SealedBoxPrivateKey::generate();
+                let sealed = old_key.public().encrypt(new_key.bytes());
+                db.write_setting(db::SETTING_PENDING_KEY, &bs58::encode(sealed).into_string()).await?;
+                new_key
+            },
+        };
 
-// impl UpgradeCommand {
-//     fn run(&self, opts: &VaultOpts) -> anyhow::Result<()> {
-//         todo!("Implement impl UpgradeCommand");
-//     }
-// }
+        // If they unlocked with their passphrase, keep using it for the new key.
+        let had_passphrase = db.read_setting(db::SETTING_WRAPPED_KEY).await?;
+        let wrapped = match &had_passphrase {
+            Some(w) if crypto::SealedBoxPrivateKey::from_wrapped(w, &secret).is_ok() => Some(new_key.wrap(&secret)?),
+            _ => None,
+        };
+
+        // Once rekey() commits, the new key is the only way in. Make sure it's saved first.
+        // Until then, running this again resumes with the same key.
+        println!("Your new PRIVATE KEY (password) is: {}", new_key);
+        println!("You must save this. Once entries are re-encrypted, the old private key no longer works.");
+        let confirm = rpassword::prompt_password_stderr("Paste the new private key to confirm you saved it: ")?;
+        if confirm.trim() != new_key.to_string() {
+            anyhow::bail!("That's not the new private key. Nothing was changed. Run 'vault rekey' again to retry.");
+        }
+
+        let had_api_token = db.read_setting(db::SETTING_API_KEY).await?.is_some();
+        let count = db.rekey(&old_key, &new_key, wrapped.as_deref()).await?;
+        db.close().await;
+
+        println!("OK. Re-encrypted {} entries with your new private key.", count);
+        if had_passphrase.is_some() && wrapped.is_none() {
+            println!("Your passphrase was removed. Run 'vault passphrase' to set a new one.");
+        }
+        if had_api_token {
+            println!("Your API token no longer works. Run 'vault api-token' to get a new one.");
+        }
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Enable the bearer token for the /api/v1 JSON API, and print it")]
+struct ApiTokenCommand {
+    #[structopt(parse(from_os_str))]
+    sqlite_file: PathBuf,
+
+    /// Disable the token instead.
+    #[structopt(long)]
+    revoke: bool,
+}
+
+impl ApiTokenCommand {
+    fn run(&self, _opts: &VaultOpts) -> anyhow::Result<()> {
+        block_on(self.async_run())
+    }
+
+    async fn async_run(&self) -> anyhow::Result<()> {
+        let db = open_db(&self.sqlite_file).await?;
+
+        if self.revoke {
+            db.delete_setting(db::SETTING_API_KEY).await?;
+            db.close().await;
+            println!("OK. The API token no longer works.");
+            return Ok(());
+        }
+
+        let secret = prompt_private_key(&db).await?;
+        let token = db.enable_api_token(&secret).await?;
+        db.close().await;
+        println!("Your API TOKEN is: {}", token);
+        println!("It can decrypt all of your entries. Keep it as safe as your private key.");
+        println!("Send it in an 'Authorization: Bearer <token>' header. Disable it with --revoke.");
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Control who can write to, and shut down, the server. Restart it to apply changes.")]
+struct AccessCommand {
+    #[structopt(parse(from_os_str))]
+    sqlite_file: PathBuf,
+
+    /// Set a password needed to write. Logging in with the private key works too.
+    #[structopt(long, conflicts_with="no-write-password")]
+    write_password: bool,
+
+    /// Let anyone who can reach the server write.
+    #[structopt(long)]
+    no_write_password: bool,
+
+    /// Whether shutting down the server needs a login: true or false.
+    #[structopt(long)]
+    shutdown_needs_login: Option<bool>,
+}
+
+impl AccessCommand {
+    fn run(&self, _opts: &VaultOpts) -> anyhow::Result<()> {
+        block_on(self.async_run())
+    }
+
+    async fn async_run(&self) -> anyhow::Result<()> {
+        let db = open_db(&self.sqlite_file).await?;
+
+        if self.write_password || self.no_write_password || self.shutdown_needs_login.is_some() {
+            // Only the owner changes the rules.
+            prompt_private_key(&db).await?;
+        }
+        if self.write_password {
+            let password = prompt_new_secret("write password")?;
+            db.write_setting(db::SETTING_WRITE_PASSWORD, &crypto::hash_password(&password)?).await?;
+        }
+        if self.no_write_password {
+            db.delete_setting(db::SETTING_WRITE_PASSWORD).await?;
+        }
+        match self.shutdown_needs_login {
+            Some(true) => db.write_setting(db::SETTING_SHUTDOWN_NEEDS_LOGIN, "true").await?,
+            Some(false) => db.delete_setting(db::SETTING_SHUTDOWN_NEEDS_LOGIN).await?,
+            None => {},
+        }
+
+        let write_password = db.read_setting(db::SETTING_WRITE_PASSWORD).await?.is_some();
+        let shutdown_needs_login = db.read_setting(db::SETTING_SHUTDOWN_NEEDS_LOGIN).await?.as_deref() == Some("true");
+        let failure_count = db.login_failure_count().await?;
+        let failures = db.recent_login_failures(10).await?;
+        db.close().await;
+        println!("Writing: {}", if write_password {
+            "needs the write password or a login"
+        } else {
+            "open to anyone who can reach the server"
+        });
+        println!("Shutting down: {}", if shutdown_needs_login {
+            "needs a login"
+        } else {
+            "open to anyone who can reach the server"
+        });
+        println!("Failed logins: {}", failure_count);
+        for failure in failures {
+            let time = chrono::Local.timestamp_millis(failure.timestamp_ms_utc);
+            println!("    {}  {}  {}", time.format("%Y-%m-%d %H:%M:%S"), failure.remote, failure.path);
+        }
+        Ok(())
+    }
+}
+
+/// A database at the current version. See `vault upgrade`.
+async fn open_db(file: &Path) -> anyhow::Result<sqlx::SqlitePool> {
+    let db = db::pool(db::options(file));
+    db::check_version(&db, file).await?;
+    Ok(db)
+}
+
+/// Read the private key, or the passphrase for it, from the terminal.
+async fn prompt_private_key(db: &sqlx::SqlitePool) -> anyhow::Result<crypto::SealedBoxPrivateKey> {
+    let secret = rpassword::prompt_password_stderr("Private key or passphrase: ")?;
+    db.unlock(&secret).await?.ok_or_else(|| anyhow::format_err!("Incorrect private key or passphrase"))
+}
+
+fn prompt_new_passphrase() -> anyhow::Result<String> {
+    prompt_new_secret("passphrase")
+}
+
+/// Read a new passphrase or password from the terminal, twice.
+fn prompt_new_secret(name: &str) -> anyhow::Result<String> {
+    const MIN_LENGTH: usize = 8;
+
+    let secret = rpassword::prompt_password_stderr(&format!("New {}: ", name))?;
+    if secret.chars().count() < MIN_LENGTH {
+        anyhow::bail!("The {} must be at least {} characters", name, MIN_LENGTH);
+    }
+    let confirm = rpassword::prompt_password_stderr(&format!("Confirm {}: ", name))?;
+    if secret != confirm {
+        anyhow::bail!("The {}s did not match", name);
+    }
+    Ok(secret)
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Upgrade database schema to a new version")]
+struct UpgradeCommand {
+    #[structopt(parse(from_os_str))]
+    sqlite_file: PathBuf,
+}
+
+impl UpgradeCommand {
+    fn run(&self, _opts: &VaultOpts) -> anyhow::Result<()> {
+        block_on(self.async_run())
+    }
+
+    async fn async_run(&self) -> anyhow::Result<()> {
+        let db = db::pool(db::options(&self.sqlite_file));
+        let version = db.get_version().await?;
+        if version == db::DB_VERSION {
+            println!("Database is already at version {}.", version);
+            return Ok(());
+        }
+        if version > db::DB_VERSION {
+            anyhow::bail!("Database version {} is greater than supported version {}", version, db::DB_VERSION);
+        }
+
+        let backup = db::backup_path(&self.sqlite_file, version);
+        db.backup(&backup).await?;
+        println!("Saved a backup to: {}", backup.to_string_lossy());
+
+        for migration in db::pending_migrations(version) {
+            println!("Version {}: {}", migration.version, migration.description);
+        }
+        db.upgrade().await
+            .with_context(|| format!("Upgrading. The backup from before is still at: {}", backup.to_string_lossy()))?;
+        db.close().await;
+
+        println!("OK. Database upgraded to version {}.", db::DB_VERSION);
+        Ok(())
+    }
+}
 
 impl VaultOpts {
     fn run(&self) -> anyhow::Result<()> {
         match &self.command {
-            MainCommands::Init(cmd) => cmd.run(&self),
-            MainCommands::Open(cmd) => cmd.run(&self),
-            MainCommands::Serve(cmd) => cmd.run(&self),
-            // MainCommands::Upgrade(cmd) => cmd.run(&self),
+            MainCommands::Init(cmd) => cmd.run(self),
+            MainCommands::Open(cmd) => cmd.run(self),
+            MainCommands::Serve(cmd) => cmd.run(self),
+            MainCommands::Upgrade(cmd) => cmd.run(self),
+            MainCommands::Passphrase(cmd) => cmd.run(self),
+            MainCommands::Export(cmd) => cmd.run(self),
+            MainCommands::Import(cmd) => cmd.run(self),
+            MainCommands::Rekey(cmd) => cmd.run(self),
+            MainCommands::ApiToken(cmd) => cmd.run(self),
+            MainCommands::Access(cmd) => cmd.run(self),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,89 @@
+//! Search decrypted entries, and highlight what matched.
+//!
+//! Nothing is indexed. Entries are only searchable while decrypted with the logged-in user's key,
+//! so the search scans them in batches.
+
+#[cfg(test)]
+mod tests;
+
+use comrak::{Arena, ComrakOptions, format_html, nodes::{AstNode, NodeValue}, parse_document};
+use regex::{Regex, RegexBuilder};
+
+// Private-use characters. They survive HTML escaping, so we can swap them for tags afterward.
+const MARK_START: char = '\u{E000}';
+const MARK_END: char = '\u{E001}';
+
+/// Show at most this many matching paragraphs for each entry.
+const SNIPPET_PARAGRAPHS: usize = 3;
+
+/// A case-insensitive search for entries containing every word in a query.
+pub(crate) struct SearchPattern {
+    terms: Vec<Regex>,
+    any: Regex,
+}
+
+impl SearchPattern {
+    /// Returns None if the query has no words.
+    pub(crate) fn new(query: &str) -> Option<Self> {
+        let words: Vec<String> = query.split_whitespace().map(regex::escape).collect();
+        if words.is_empty() {
+            return None;
+        }
+
+        let build = |pattern: &str| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .expect("Escaped words should be a valid regex")
+        };
+        Some(Self {
+            terms: words.iter().map(|w| build(w)).collect(),
+            any: build(&words.join("|")),
+        })
+    }
+
+    pub(crate) fn matches(&self, text: &str) -> bool {
+        self.terms.iter().all(|term| term.is_match(text))
+    }
+
+    /// The paragraphs of some markdown that contain a match.
+    pub(crate) fn snippet(&self, markdown: &str) -> String {
+        let paragraphs: Vec<&str> = markdown
+            .split("\n\n")
+            .filter(|p| self.any.is_match(p))
+            .take(SNIPPET_PARAGRAPHS)
+            .collect();
+        paragraphs.join("\n\n")
+    }
+
+    /// Render markdown to HTML, with matches wrapped in <mark> tags.
+    pub(crate) fn render_highlighted(&self, markdown: &str, options: &ComrakOptions) -> String {
+        let markdown = markdown.replace([MARK_START, MARK_END], "");
+
+        let arena = Arena::new();
+        let root = parse_document(&arena, &markdown, options);
+        for node in root.descendants() {
+            if in_image(node) {
+                // Image alt text is rendered into an attribute. Can't put a tag there.
+                continue;
+            }
+            if let NodeValue::Text(ref mut text) = node.data.borrow_mut().value {
+                let marked = self.any.replace_all(
+                    std::str::from_utf8(text).unwrap_or_default(),
+                    format!("{}$0{}", MARK_START, MARK_END).as_str(),
+                );
+                *text = marked.into_owned().into_bytes();
+            }
+        }
+
+        let mut html = Vec::new();
+        format_html(root, options, &mut html).expect("Writing to a Vec shouldn't fail");
+        String::from_utf8_lossy(&html)
+            .replace(MARK_START, "<mark>")
+            .replace(MARK_END, "</mark>")
+    }
+}
+
+fn in_image<'a>(node: &'a AstNode<'a>) -> bool {
+    node.ancestors().any(|n| matches!(n.data.borrow().value, NodeValue::Image(_)))
+}
@@ -0,0 +1,33 @@
+use comrak::ComrakOptions;
+
+use super::SearchPattern;
+
+#[test]
+fn test_matches_all_words() {
+    let pattern = SearchPattern::new("  Rust   vault ").unwrap();
+    assert!(pattern.matches("I wrote a VAULT in rust."));
+    assert!(!pattern.matches("I wrote a vault."));
+
+    assert!(SearchPattern::new("   ").is_none());
+}
+
+#[test]
+fn test_snippet() {
+    let pattern = SearchPattern::new("cat").unwrap();
+    let snippet = pattern.snippet("# Monday\n\nSaw a cat.\n\nAte lunch.\n\nThe CAT again.");
+    assert_eq!(snippet, "Saw a cat.\n\nThe CAT again.");
+}
+
+#[test]
+fn test_render_highlighted() {
+    let pattern = SearchPattern::new("cat a.b").unwrap();
+    let html = pattern.render_highlighted(
+        "A *Cat* and a.b, not axb. [cat](http://cat.example) ![cat](cat.png) \\<cat\\>",
+        &ComrakOptions::default(),
+    );
+    assert_eq!(html, concat!(
+        "<p>A <em><mark>Cat</mark></em> and <mark>a.b</mark>, not axb. ",
+        "<a href=\"http://cat.example\"><mark>cat</mark></a> ",
+        "<img src=\"cat.png\" alt=\"cat\" /> &lt;<mark>cat</mark>&gt;</p>\n",
+    ));
+}
@@ -1,21 +1,32 @@
-use std::{borrow::Cow, sync::Arc, time::Duration};
+mod api;
+mod client_side;
+mod listen;
+mod session;
+mod throttle;
+
+#[cfg(test)]
+mod tests;
+
+use std::{borrow::Cow, collections::{BTreeSet, HashMap}, net::{IpAddr, SocketAddr}, sync::Arc, time::{Duration, Instant}};
 
 use anyhow::{Context};
 use async_std::sync::Mutex;
 use async_trait::async_trait;
-use chrono::{FixedOffset, Offset};
-use comrak::{ComrakOptions, markdown_to_html};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, Offset, TimeZone};
+use comrak::ComrakOptions;
 use serde::{Serialize, Deserialize};
 
 use stop_token::future::FutureExt as _;
 use tera_embed::{TeraEmbed, TideTeraRender, rust_embed::{self, RustEmbed}};
 use tide::{Response, http::{Cookie}};
 
-use crate::{OpenCommand, VaultOpts, crypto::{
+use crate::{OpenCommand, VaultOpts, archive, crypto::{
+        self,
         SealedBoxPrivateKey,
         SealedBoxPublicKey,
-        SecretBox
-    }, db::{self, Entry, VaultExt}, statics};
+        SecretBox,
+        StreamKey,
+    }, db::{self, Entry, VaultExt}, links, search::SearchPattern, statics, tags};
 
 #[derive(Clone)]
 struct AppState {
@@ -28,6 +39,11 @@ struct AppState {
     markdown_opts: ComrakOptions,
     db: sqlx::SqlitePool,
     secret_box: SecretBox,
+    /// Served over HTTPS, so cookies can be Secure.
+    tls: bool,
+    access: Access,
+    session_limits: session::Limits,
+    login_throttle: Arc<throttle::LoginThrottle>,
 
     // TODO: Just for testing. Store public key in the DB.
     public_key: SealedBoxPublicKey,
@@ -35,7 +51,11 @@ struct AppState {
 
 type AppRequest = tide::Request<AppState>;
 
-const PRIV_KEY_COOKIE: &'static str = "login";
+const PRIV_KEY_COOKIE: &str = "login";
+/// Set by logging in with the write password.
+const WRITE_COOKIE: &str = "write";
+/// Holds the CSRF token that forms must send back. See Csrf.
+const CSRF_COOKIE: &str = "csrf";
 
 trait RequestExt {
     fn page(&self, title: impl Into<Cow<'static,str>>) -> Page;
@@ -47,10 +67,25 @@ trait RequestExt {
     fn decrypt_bytes(&self, cookie: &Cookie) -> anyhow::Result<Option<Vec<u8>>>;
     fn encrypt_bytes(&self, cookie: &mut Cookie, data: &[u8] );
 
+    /// A live session from an encrypted cookie. See session.rs.
+    /// None if it's missing or ended. Err if we couldn't decrypt.
+    fn login_session(&self, name: &str) -> anyhow::Result<Option<session::Session>>;
+    fn session_cookie(&self, name: &'static str, session: &session::Session) -> Cookie<'static>;
+
     // If the user is logged in w/ their private key, we can decrypt posts:
     fn get_priv_key(&self) -> anyhow::Result<Option<SealedBoxPrivateKey>>;
     fn logged_in(&self) -> bool;
+    /// Like get_priv_key(), but a cookie we can't decrypt just means the user isn't logged in.
+    fn session_key(&self) -> Option<SealedBoxPrivateKey>;
     fn set_priv_key(&self, key: &[u8]) -> Cookie<'static>;
+
+    /// Logged in with the write password. See `vault access`.
+    fn write_unlocked(&self) -> bool;
+    fn set_write_unlocked(&self) -> Cookie<'static>;
+    /// Anyone can write, unless there's a write password.
+    fn can_write(&self) -> bool;
+    /// Any kind of login. The write password counts, since there's no other login in client-side mode.
+    fn can_shut_down(&self) -> bool;
     // fn db(&self) -> sqlx::SqliteConnection;
 }
 
@@ -65,7 +100,7 @@ impl RequestExt for AppRequest {
     }
 
     fn render_markdown(&self, md: &str) -> String {
-        markdown_to_html(md, &self.state().markdown_opts)
+        links::render_markdown(md, &self.state().markdown_opts)
     }
 
     fn decrypt_bytes(&self, cookie: &Cookie) -> anyhow::Result<Option<Vec<u8>>> {
@@ -79,30 +114,66 @@ impl RequestExt for AppRequest {
         cookie.set_value(bs58::encode(cypher).into_string());
     }
 
-    fn get_priv_key(&self) -> anyhow::Result<Option<SealedBoxPrivateKey>> {
-        let cookie = match self.cookie(PRIV_KEY_COOKIE) {
+    fn login_session(&self, name: &str) -> anyhow::Result<Option<session::Session>> {
+        let cookie = match self.cookie(name) {
             Some(c) => c,
             None => return Ok(None),
         };
-        let key_bytes = match self.decrypt_bytes(&cookie)? {
+        let bytes = match self.decrypt_bytes(&cookie)? {
             None => return Ok(None),
             Some(b) => b,
         };
+        let session = session::Session::from_bytes(&bytes).context("Invalid session cookie")?;
+        Ok(Some(session).filter(|s| s.is_live(&self.state().session_limits, session::now())))
+    }
 
-        Ok(Some(SealedBoxPrivateKey::from_bytes(&key_bytes)?))
+    fn session_cookie(&self, name: &'static str, session: &session::Session) -> Cookie<'static> {
+        let remaining = session.remaining(&self.state().session_limits, session::now());
+        let mut cookie = Cookie::build(name, "")
+            .path("/")
+            .http_only(true)
+            .secure(self.state().tls)
+            .max_age(time::Duration::seconds(remaining.as_secs() as i64))
+            .finish();
+        self.encrypt_bytes(&mut cookie, &session.to_bytes());
+        cookie
+    }
+
+    fn get_priv_key(&self) -> anyhow::Result<Option<SealedBoxPrivateKey>> {
+        let session = match self.login_session(PRIV_KEY_COOKIE)? {
+            None => return Ok(None),
+            Some(s) => s,
+        };
+
+        Ok(Some(SealedBoxPrivateKey::from_bytes(&session.data)?))
     }
 
     fn set_priv_key(&self, key: &[u8]) -> Cookie<'static> {
-        let mut cookie = Cookie::build(PRIV_KEY_COOKIE, "").finish();
-        self.encrypt_bytes(&mut cookie, key);
-        cookie
+        self.session_cookie(PRIV_KEY_COOKIE, &session::Session::new(key, session::now()))
     }
 
     fn logged_in(&self) -> bool {
-        match self.get_priv_key() {
-            Ok(Some(_key)) => true,
-            _ => false,
-        }
+        self.session_key().is_some()
+    }
+
+    fn write_unlocked(&self) -> bool {
+        matches!(self.login_session(WRITE_COOKIE), Ok(Some(session)) if session.data == WRITE_COOKIE.as_bytes())
+    }
+
+    fn set_write_unlocked(&self) -> Cookie<'static> {
+        self.session_cookie(WRITE_COOKIE, &session::Session::new(WRITE_COOKIE.as_bytes(), session::now()))
+    }
+
+    fn can_write(&self) -> bool {
+        self.state().access.write_password.is_none() || self.logged_in() || self.write_unlocked()
+    }
+
+    fn can_shut_down(&self) -> bool {
+        !self.state().access.shutdown_needs_login || self.logged_in() || self.write_unlocked()
+    }
+
+    fn session_key(&self) -> Option<SealedBoxPrivateKey> {
+        self.get_priv_key().ok().flatten()
     }
 
     
@@ -123,45 +194,150 @@ pub(crate) async fn async_run_server(opts: &VaultOpts, command: &OpenCommand) ->
 
     let pool = db::pool(db::options(&command.opts.sqlite_file));
 
-    if pool.needs_upgrade().await? {
-        anyhow::bail!("Database needs an upgrade");
-    }
+    db::check_version(&pool, &command.opts.sqlite_file).await?;
 
     let public_key = pool.public_key().await.context("getting public key")?;
+    let listen = listen::Listen::from_opts(&command.opts)?;
+    let access = Access {
+        write_password: pool.read_setting(db::SETTING_WRITE_PASSWORD).await?,
+        shutdown_needs_login: pool.read_setting(db::SETTING_SHUTDOWN_NEEDS_LOGIN).await?.as_deref() == Some("true"),
+    };
 
     let stopper = stop_token::StopSource::new();
     let stop = stopper.token();
 
     sodiumoxide::init().map_err(|_| anyhow::format_err!("Error initializing sodiumoxide."))?;
+    let secret_box = match &command.opts.session_key_file {
+        Some(file) => session::load_key(&pool, file).await?,
+        None => SecretBox::generate(),
+    };
     let state = AppState {
         db: pool,
         templates: TeraEmbed::new(),
         markdown_opts: ComrakOptions::default(),
         stopper: Arc::new(Mutex::new(stopper)),
-        secret_box: SecretBox::generate(),
+        secret_box,
+        tls: listen.is_tls(),
+        access,
+        session_limits: session::Limits{
+            lifetime: Duration::from_secs(command.opts.session_hours * 60 * 60),
+            idle: Duration::from_secs(command.opts.idle_minutes * 60),
+        },
+        login_throttle: Default::default(),
         public_key,
-        nav: vec![
-            NavItem::new("Write", "/"),
-            NavItem::hidden("Log In", "/login"),
-            NavItem::new("Read", "/read"),
-            NavItem::new("Shutdown", "/shutdown"),
-        ],
+        nav: if command.opts.client_side {
+            client_side::nav()
+        } else {
+            vec![
+                NavItem::new("Write", "/"),
+                NavItem::hidden("Log In", "/login"),
+                NavItem::new("Read", "/read"),
+                NavItem::new("On This Day", "/on-this-day"),
+                NavItem::new("Archive", "/archive"),
+                NavItem::new("Search", "/search"),
+                NavItem::new("Tags", "/tags"),
+                NavItem::post("Lock", "/logout"),
+                NavItem::new("Shutdown", "/shutdown"),
+            ]
+        },
     };
 
+    let app = app(state, command.opts.client_side);
+
+    println!("Server running at: {}", &listen);
+    let url = listen.url();
+    let server = listen.listen(app);
+
+    if let (false, Some(url)) = (command.opts.no_browser, url) {
+        match webbrowser::open(&url) {
+            Ok(_) => {},
+            Err(_) => {
+                println!("Couldn't open browser.");
+            }
+        }
+    }
+
+    match server.until(stop).await {
+        Ok(server_result) => {
+            println!("Server error.");
+            Ok(server_result?)
+        },
+        Err(_io_err) =>  {
+            // User requested server stop.
+            Ok(())
+        }
+    }
+}
 
+/// All of the routes, and the middleware in front of them.
+fn app(state: AppState, client_side: bool) -> tide::Server<AppState> {
     let mut app = tide::with_state(state);
     app.with(NoStore{});
+    app.with(Csrf{});
+    app.with(session::Sessions{});
 
-    app.at("/").get(|req: AppRequest| async move {
-        req.render("write.html", Write {
-            page: req.page("Write"),
-            post: String::new(),
-            preview_html: String::new(),
-        })
+    if client_side {
+        client_side::routes(&mut app);
+    } else {
+        server_side_routes(&mut app);
+    }
+
+    app.at("/shutdown").get(|req: AppRequest| async move {
+        if !req.can_shut_down() {
+            return login_redirect();
+        }
+        let body = req.render("shutdown.html", Message{
+            page: Page::new(&req, "Shutdown"),
+            message: "Stop the server? You'll need to start it again from the command line.".into(),
+        })?;
+        Ok(body.into())
+    })
+    // A POST, so a link or image on another website can't stop the server.
+    .post(|req: AppRequest| async move {
+        if !req.can_shut_down() {
+            return login_redirect();
+        }
+        let stopper = req.state().stopper.clone();
+
+        async_std::task::spawn(async move {
+            async_std::task::sleep(Duration::from_millis(500)).await;
+            println!("User requested shutdown.");
+            let mut lock = stopper.lock().await;
+            
+            // Replace w/ a new, unrelated stopper, to let the old stopper stop.
+            let stopper = std::mem::replace(&mut *lock, stop_token::StopSource::new());
+            drop(stopper);
+        });
+
+        let body = req.render("message.html", Message{
+            page: Page::new(&req, "Shutting Down"),
+            message: "The server will now shut down.".into()
+        })?;
+        Ok(body.into())
     });
 
+    app.at("/logout").post(|_req: AppRequest| async move {
+        let mut res: Response = tide::Redirect::see_other("/login").into();
+        res.remove_cookie(session::ended(PRIV_KEY_COOKIE));
+        res.remove_cookie(session::ended(WRITE_COOKIE));
+        res.insert_ext(session::Changed);
+        Ok(res)
+    });
+
+    app.at("/static/*path").get(statics::serve::<Statics, AppState>);
+
+    app
+}
+
+/// Routes for the usual mode, where the server encrypts and decrypts.
+fn server_side_routes(app: &mut tide::Server<AppState>) {
+    app.at("/").get(write_page);
+
     app.at("/").post(|mut req: AppRequest| async move {
-        let WritePost{mut post, preview, submit} = req.body_form().await?;
+        if !req.can_write() {
+            return login_redirect();
+        }
+        let (WritePost{mut post, mut tags, mut draft, preview, submit}, uploads) = read_write_form(&mut req).await?;
 
         let mut page = req.page("Write");
         let mut preview_html = String::new();
@@ -169,199 +345,1199 @@ pub(crate) async fn async_run_server(opts: &VaultOpts, command: &OpenCommand) ->
         if submit.is_some() {
             let db = &req.state().db;
             let key = &req.state().public_key;
-            let now = chrono::Local::now();
-            let entry = Entry{
-                timestamp_ms_utc: now.timestamp_millis(),
-                offset_utc_mins: now.offset().fix().local_minus_utc() / 60,
-                contents: key.encrypt(post.as_bytes()),
-            };
-            db.write_entry(entry).await?;
+            write_new_entry(db, key, &post, &tags, uploads).await?;
+            if !draft.is_empty() {
+                db.delete_draft(&draft).await?;
+            }
             post = String::new();
+            tags = String::new();
+            draft = random_token();
             page.flash_success("Post saved.");
 
         } else if preview.is_some() {
-            preview_html = req.render_markdown(&post)
+            preview_html = req.render_markdown(&post);
+            if !uploads.is_empty() {
+                // Browsers won't let us fill in a file input, so there's nothing to keep them in.
+                page.flash_warning("Attachments aren't kept when previewing. Choose them again before submitting.");
+            }
         } 
 
-        req.render("write.html", Write { page, post, preview_html })
+        if draft.is_empty() {
+            draft = random_token();
+        }
+        let body = req.render("write.html", Write { page, post, tags, preview_html, draft, saved_draft: None })?;
+        Ok(body.into())
     });
 
+    app.at("/draft")
+    .post(save_draft);
+
+    app.at("/draft/:token/delete")
+    .post(delete_draft);
+
     app.at("/read")
     .get(read_posts);
 
+    app.at("/search")
+    .get(search);
+
+    app.at("/tags")
+    .get(tag_list);
+
+    app.at("/on-this-day")
+    .get(on_this_day);
+
+    app.at("/archive")
+    .get(archive);
+
+    app.at("/archive/:year/:month")
+    .get(archive_month);
+
+    app.at("/entry/:timestamp")
+    .get(show_entry);
+
+    app.at("/attachment/:id")
+    .get(show_attachment);
+
+    app.at("/entry/:timestamp/edit")
+    .get(edit_entry)
+    .post(edit_entry);
+
+    app.at("/entry/:timestamp/history")
+    .get(entry_history);
+
+    app.at("/entry/:timestamp/delete")
+    .post(delete_entry);
+
     app.at("/login")
     .get(|req: AppRequest| async move {
-        req.render("login.html", LogIn{
-            page: req.page("Log In")
-        })
+        req.render("login.html", LogIn::new(&req, true))
     })
     .post(|mut req: AppRequest| async move {
+        if let Some(res) = login_throttled(&req, true)? {
+            return Ok(res);
+        }
         let form: LogInForm = req.body_form().await?;
-        let secret = SealedBoxPrivateKey::from_base58(&form.secret);
-
-        match secret {
-            Err(err) => println!("Bad secret. {:?}", err),
-            Ok(secret) => {
-                let server_key = &req.state().public_key;
-
-                if secret.public() == server_key {
-                    let mut res: Response = tide::Redirect::see_other("/read").into();
-                    let cookie = req.set_priv_key(secret.bytes());
-                    res.insert_cookie(cookie);
-                    return Ok(res);
-                } 
-                println!("Login attempt with incorrect private key.");
-
-                // TRY treating the private key as a seed.
-                // The Deno version used to hand out the seed.
-                if let Ok(secret) = SealedBoxPrivateKey::from_base58_seed(&form.secret) {
-                    if secret.public() == server_key {
-                        println!("You supplied the seed for the private key.");
-                        println!("Instead, use the private key: {}", &secret);
-                    }
-                }
+
+        if let Some(secret) = req.state().db.unlock(&form.secret).await? {
+            login_succeeded(&req);
+            let mut res: Response = tide::Redirect::see_other("/read").into();
+            let cookie = req.set_priv_key(secret.bytes());
+            res.insert_cookie(cookie);
+            res.insert_ext(session::Changed);
+            return Ok(res);
+        }
+        if let Some(res) = write_login(&req, &form.secret).await {
+            return Ok(res);
+        }
+
+        // TRY treating the private key as a seed.
+        // The Deno version used to hand out the seed.
+        if let Ok(secret) = SealedBoxPrivateKey::from_base58_seed(form.secret.trim()) {
+            if secret.public() == &req.state().public_key {
+                login_succeeded(&req);
+                let mut page = req.page("Log In");
+                page.flash_warning("You logged in with your seed, not your private key.");
+                let body = req.render("seed.html", SeedLogIn{
+                    page,
+                    private_key: secret.to_string(),
+                })?;
+                let mut res: Response = body.into();
+                res.insert_cookie(req.set_priv_key(secret.bytes()));
+                res.insert_ext(session::Changed);
+                return Ok(res);
             }
         }
-        
-        let body = req.render("login.html", LogIn{
-            page: req.page("Log In")
-        })?;
+
+        println!("Login attempt with incorrect private key or passphrase.");
+        login_failed(&req).await?;
+        let mut login = LogIn::new(&req, true);
+        login.page.flash_error(if login.write_password {
+            "Incorrect private key, passphrase, or write password."
+        } else {
+            "Incorrect private key or passphrase."
+        });
+        let body = req.render("login.html", login)?;
         Ok(body.into())
     }) ;
 
-    app.at("/shutdown").get(|req: AppRequest| async move {
-        let stopper = req.state().stopper.clone();
+    // The API encrypts and decrypts on the server.
+    api::routes(app.at("/api/v1"));
+}
 
-        async_std::task::spawn(async move {
-            async_std::task::sleep(Duration::from_millis(500)).await;
-            println!("User requested shutdown.");
-            let mut lock = stopper.lock().await;
-            
-            // Replace w/ a new, unrelated stopper, to let the old stopper stop.
-            let stopper = std::mem::replace(&mut *lock, stop_token::StopSource::new());
-            drop(stopper);
-        });
+async fn write_page(req: AppRequest) -> tide::Result<tide::Response> {
+    if !req.can_write() {
+        return login_redirect();
+    }
+    let mut write = Write {
+        page: req.page("Write"),
+        post: String::new(),
+        tags: String::new(),
+        preview_html: String::new(),
+        draft: random_token(),
+        saved_draft: None,
+    };
 
-        req.render("message.html", Message{
-            page: Page::new(&req, "Shutting Down"),
-            message: "The server will now shut down.".into()
+    // Drafts are sealed, so we can only offer them after logging in:
+    if let Some(key) = req.session_key() {
+        let query: WriteQuery = req.query()?;
+        let db = &req.state().db;
+        match query.draft {
+            Some(token) => match db.get_draft(&token).await? {
+                Some(draft) => {
+                    write.post = key.decrypt_string(&draft.contents)?;
+                    write.tags = key.decrypt_string(&draft.tags)?;
+                    write.draft = draft.token;
+                    write.page.flash_success("Draft restored.");
+                },
+                None => write.page.flash_warning("That draft was already submitted or discarded."),
+            },
+            None => if let Some(draft) = db.latest_draft().await? {
+                let saved = chrono::Local.timestamp_millis(draft.saved_ms_utc);
+                write.saved_draft = Some(SavedDraft {
+                    token: draft.token,
+                    saved: format_time(saved.with_timezone(&saved.offset().fix())),
+                });
+            },
+        }
+    }
+
+    let body = req.render("write.html", write)?;
+    Ok(body.into())
+}
+
+/// Called in the background by the write page. Doesn't need a login, like writing.
+async fn save_draft(mut req: AppRequest) -> tide::Result<tide::Response> {
+    if !req.can_write() {
+        return Ok(Response::new(tide::StatusCode::Forbidden));
+    }
+    let form: DraftForm = req.body_form().await?;
+    if form.draft.is_empty() || form.draft.len() > 64 {
+        return Err(tide::Error::from_str(tide::StatusCode::BadRequest, "Invalid draft token"));
+    }
+
+    let db = &req.state().db;
+    if form.post.trim().is_empty() && form.tags.trim().is_empty() {
+        db.delete_draft(&form.draft).await?;
+    } else {
+        let key = &req.state().public_key;
+        db.save_draft(&db::Draft {
+            token: form.draft,
+            saved_ms_utc: chrono::Utc::now().timestamp_millis(),
+            contents: key.encrypt(form.post.as_bytes()),
+            tags: key.encrypt(form.tags.as_bytes()),
+        }).await?;
+    }
+    Ok(Response::new(tide::StatusCode::NoContent))
+}
+
+async fn delete_draft(req: AppRequest) -> tide::Result<tide::Response> {
+    if !req.logged_in() {
+        return login_redirect();
+    }
+
+    req.state().db.delete_draft(req.param("token")?).await?;
+    Ok(tide::Redirect::see_other("/").into())
+}
+
+/// Identifies a draft while it's being written.
+/// For drafts and CSRF.
+fn random_token() -> String {
+    bs58::encode(sodiumoxide::randombytes::randombytes(16)).into_string()
+}
+
+async fn read_posts(req: AppRequest) -> tide::Result<tide::Response> {
+    let key = match req.session_key() {
+        Some(key) => key,
+        None => return login_redirect(),
+    };
+
+    let query: ReadQuery = req.query()?;
+
+    let db = &req.state().db;
+    let tag = query.tag.as_deref().map(tags::normalize).filter(|t| !t.is_empty());
+    let tag_hash = match &tag {
+        Some(tag) => Some(tag_hash(db, &key, tag).await?),
+        None => None,
+    };
+
+    let entries = db.get_posts(&query, tag_hash.as_deref()).await?;
+    let mut backlinks = find_backlinks(db, &key, &entries, &req.state().markdown_opts).await?;
+    let timestamps = entries.iter().map(|e| e.timestamp_ms_utc);
+    let (edits, mut entry_tags, mut attachments) = match (timestamps.clone().min(), timestamps.max()) {
+        (Some(from), Some(to)) => (
+            db.revision_counts(from, to).await?,
+            tags::decrypt_tags(db, &key, from, to).await?,
+            decrypt_attachments(db, &key, from, to).await?,
+        ),
+        _ => (HashMap::new(), HashMap::new(), HashMap::new()),
+    };
+    let posts: anyhow::Result<Vec<Post>> = entries
+        .into_iter()
+        .map(|e| {
+            let edits = edits.get(&e.timestamp_ms_utc).copied().unwrap_or(0);
+            let tags = entry_tags.remove(&e.timestamp_ms_utc).unwrap_or_default();
+            let attachments = attachments.remove(&e.timestamp_ms_utc).unwrap_or_default();
+            let backlinks = backlinks.remove(&e.timestamp_ms_utc).unwrap_or_default();
+            entry_to_post(e, &req, &key).map(|post| Post{ edits, tags, attachments, backlinks, ..post })
         })
-    });
+        .collect();
+    let posts = posts?;
 
-    app.at("/static/*path").get(statics::serve::<Statics, AppState>);
+    let mut page = match &tag {
+        Some(tag) => req.page(format!("Posts Tagged #{}", tag)),
+        None => req.page("Read Posts"),
+    };
 
-    let host_and_port = format!("127.0.0.1:{port}", port=command.opts.port);
+    let ids: Vec<i64> = posts.iter().map(|p| p.id).collect();
+    paging_links(&req, &query, tag.as_deref(), &ids, &mut page);
 
-    let server = app.listen(&host_and_port);
+    let posts = Posts{
+        page,
+        posts,
+        chronological: query.chronological,
+        from: query.from.map(|d| d.to_string()).unwrap_or_default(),
+        to: query.to.map(|d| d.to_string()).unwrap_or_default(),
+        tag,
+    };
+    let res: Response = match req.render("posts.html", posts) {
+        Ok(body) => body.into(),
+        Err(err) => err.into(),
+    };
+    Ok(res)
+}
 
-    let url = format!("http://{}/", host_and_port);
+/// Link to the pages before and after a page of entries, keeping the other options.
+fn paging_links(req: &AppRequest, query: &ReadQuery, tag: Option<&str>, ids: &[i64], page: &mut Page) {
+    let link = |before: Option<i64>, after: Option<i64>| {
+        let mut url = req.url().clone();
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs.clear();
+            if let Some(limit) = query.limit {
+                pairs.append_pair("limit", &limit.to_string());
+            }
+            if let Some(tag) = tag {
+                pairs.append_pair("tag", tag);
+            }
+            if query.chronological {
+                pairs.append_pair("chronological", "true");
+            }
+            if let Some(from) = query.from {
+                pairs.append_pair("from", &from.to_string());
+            }
+            if let Some(to) = query.to {
+                pairs.append_pair("to", &to.to_string());
+            }
+            if let Some(before) = before {
+                pairs.append_pair("before", &before.to_string());
+            }
+            if let Some(after) = after {
+                pairs.append_pair("after", &after.to_string());
+            }
+        }
+        format!("{}?{}", url.path(), url.query().unwrap_or_default())
+    };
+    // Cursors that continue the list toward its end, or back toward its start:
+    let cursors = |id: i64| if query.chronological { (None, Some(id)) } else { (Some(id), None) };
+    let back_cursors = |id: i64| if query.chronological { (Some(id), None) } else { (None, Some(id)) };
+
+    let full_page = ids.len() == query.limit();
+    let has_previous = if query.paging_back() { full_page } else { query.before.is_some() || query.after.is_some() };
+    let has_next = query.paging_back() || full_page;
+    if let (true, Some(first)) = (has_previous, ids.first()) {
+        let (before, after) = back_cursors(*first);
+        page.previous.replace(NavItem::new("Previous", link(before, after)));
+    }
+    if let (true, Some(last)) = (has_next, ids.last()) {
+        let (before, after) = cursors(*last);
+        page.next.replace(NavItem::new("Next", link(before, after)));
+    }
+}
 
-    if !command.opts.no_browser {
-        match webbrowser::open(&url) {
-            Ok(_) => {},
-            Err(_) => {
-                println!("Couldn't open browser.");
+async fn search(req: AppRequest) -> tide::Result<tide::Response> {
+    // Entries to decrypt at a time, and results to show on one page:
+    const BATCH_SIZE: usize = 100;
+    const MAX_RESULTS: usize = 20;
+
+    let key = match req.session_key() {
+        Some(key) => key,
+        None => return login_redirect(),
+    };
+
+    let query: SearchQuery = req.query()?;
+    let mut page = req.page("Search");
+    let mut results = Vec::new();
+
+    if let Some(pattern) = SearchPattern::new(&query.q) {
+        let db = &req.state().db;
+        let mut before = query.before;
+        let mut more = false;
+
+        'scan: loop {
+            let batch = db.entries_before(before, BATCH_SIZE).await?;
+            if batch.is_empty() {
+                break;
+            }
+            for entry in batch {
+                if results.len() == MAX_RESULTS {
+                    more = true;
+                    break 'scan;
+                }
+                before = Some(entry.timestamp_ms_utc);
+
+                let markdown = key.decrypt_string(&entry.contents)?;
+                if !pattern.matches(&markdown) {
+                    continue;
+                }
+                let snippet = pattern.snippet(&markdown);
+                results.push(Post {
+                    id: entry.timestamp_ms_utc,
+                    html: pattern.render_highlighted(&snippet, &req.state().markdown_opts),
+                    timestamp: format_time(entry.local_time()),
+                    edits: 0,
+                    tags: Vec::new(),
+                    attachments: Vec::new(),
+                    backlinks: Vec::new(),
+                });
             }
         }
+
+        if more {
+            let mut url = req.url().clone();
+            url.query_pairs_mut()
+                .clear()
+                .append_pair("q", &query.q)
+                .append_pair("before", &before.unwrap_or_default().to_string());
+            let link = format!("{}?{}", url.path(), url.query().unwrap_or_default());
+            page.next.replace(NavItem::new("More", link));
+        }
     }
 
-    println!("Server running at: {}", &url);
+    let body = req.render("search.html", Search {
+        page,
+        q: query.q,
+        results,
+    })?;
+    Ok(body.into())
+}
 
-    match server.until(stop).await {
-        Ok(server_result) => {
-            println!("Server error.");
-            return Ok(server_result?);
-        },
-        Err(_io_err) =>  {
-            // User requested server stop.
-            return Ok(())
+async fn tag_list(req: AppRequest) -> tide::Result<tide::Response> {
+    let key = match req.session_key() {
+        Some(key) => key,
+        None => return login_redirect(),
+    };
+
+    let db = &req.state().db;
+    db.index_tags(&key).await?;
+    let mut tag_counts = Vec::new();
+    for (tag, count) in db.tag_counts().await? {
+        tag_counts.push(TagCount {
+            name: key.decrypt_string(&tag)?,
+            count,
+        });
+    }
+    tag_counts.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let body = req.render("tags.html", Tags {
+        page: req.page("Tags"),
+        tags: tag_counts,
+    })?;
+    Ok(body.into())
+}
+
+async fn on_this_day(req: AppRequest) -> tide::Result<tide::Response> {
+    let key = match req.session_key() {
+        Some(key) => key,
+        None => return login_redirect(),
+    };
+
+    let query: OnThisDayQuery = req.query()?;
+    let date = query.date.unwrap_or_else(|| chrono::Local::today().naive_local());
+
+    let mut years: Vec<YearPosts> = Vec::new();
+    for entry in req.state().db.entries_on_this_day(date).await? {
+        let year = entry.local_time().year();
+        let post = entry_to_post(entry, &req, &key)?;
+        match years.last_mut() {
+            Some(last) if last.year == year => last.posts.push(post),
+            _ => years.push(YearPosts { year, posts: vec![post] }),
         }
     }
+
+    let mut page = req.page(format!("On This Day: {}", date.format("%B %e")));
+    let day_link = |date: NaiveDate| format!("{}?date={}", req.url().path(), date);
+    if let Some(previous) = date.pred_opt() {
+        page.previous.replace(NavItem::new("Previous", day_link(previous)));
+    }
+    if let Some(next) = date.succ_opt() {
+        page.next.replace(NavItem::new("Next", day_link(next)));
+    }
+
+    let body = req.render("on_this_day.html", OnThisDay {
+        page,
+        date: date.to_string(),
+        years,
+    })?;
+    Ok(body.into())
 }
 
-async fn read_posts(req: AppRequest) -> tide::Result<tide::Response> {
+async fn archive(req: AppRequest) -> tide::Result<tide::Response> {
     if !req.logged_in() {
-        let res: Response = tide::Redirect::temporary("/login").into();
-        return Ok(res);
+        return login_redirect();
     }
 
-    let key = req.get_priv_key()?.expect("User is logged in");
+    let counts: HashMap<String, i64> = req.state().db.month_counts().await?.into_iter().collect();
+    let years: BTreeSet<i32> = counts.keys()
+        .filter_map(|month| month.get(..4)?.parse().ok())
+        .collect();
 
-    let query: ReadQuery = req.query()?;
+    let mut archive_years = Vec::new();
+    for &year in years.iter().rev() {
+        let months: Vec<ArchiveMonth> = (1..=12).filter_map(|month| {
+            let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+            Some(ArchiveMonth {
+                name: first.format("%b").to_string(),
+                link: month_link(year, month),
+                count: counts.get(&first.format("%Y-%m").to_string()).copied().unwrap_or(0),
+            })
+        }).collect();
+        archive_years.push(ArchiveYear {
+            year,
+            total: months.iter().map(|m| m.count).sum(),
+            months,
+        });
+    }
 
-    let db = &req.state().db;
-    let posts: anyhow::Result<Vec<Post>> = db
-        .get_posts(&query)
-        .await?
+    let body = req.render("archive.html", Archive {
+        page: req.page("Archive"),
+        years: archive_years,
+    })?;
+    Ok(body.into())
+}
+
+async fn archive_month(req: AppRequest) -> tide::Result<tide::Response> {
+    if !req.logged_in() {
+        return login_redirect();
+    }
+
+    let bad_request = |e| tide::Error::from_str(tide::StatusCode::BadRequest, e);
+    let year: i32 = req.param("year")?.parse().map_err(|_| bad_request("Invalid year"))?;
+    let month: u32 = req.param("month")?.parse().map_err(|_| bad_request("Invalid month"))?;
+    let weeks = archive::month_weeks(year, month).ok_or_else(|| bad_request("Invalid month"))?;
+
+    let first = NaiveDate::from_ymd(year, month, 1);
+    let counts: HashMap<String, i64> = req.state().db
+        .day_counts(&first.format("%Y-%m").to_string()).await?
         .into_iter()
-        .map(|e| entry_to_post(e, &req, &key))
         .collect();
-    let posts = posts?;
 
-    let mut page = req.page("Read Posts");
-    let offset = query.offset.unwrap_or(0);
-    let limit = query.limit.unwrap_or(50);
-    if offset > 0 {
-        page.previous.replace(NavItem::new(
-            "Previous", 
-            format!("{}?offset={}&limit={}", req.url().path(), offset.saturating_sub(limit), limit)
-        ));
+    let weeks = weeks.iter().map(|week| {
+        week.iter().map(|day| day.map(|day| {
+            let date = day.to_string();
+            let count = counts.get(&date).copied().unwrap_or(0);
+            CalendarDay {
+                day: day.day(),
+                count,
+                link: if count > 0 {
+                    Some(format!("/read?from={date}&to={date}&chronological=true", date=date))
+                } else {
+                    None
+                },
+            }
+        })).collect()
+    }).collect();
+
+    let mut page = req.page(first.format("%B %Y").to_string());
+    let (prev_year, prev_month) = archive::previous_month(year, month);
+    page.previous.replace(NavItem::new("Previous", month_link(prev_year, prev_month)));
+    let (next_year, next_month) = archive::next_month(year, month);
+    page.next.replace(NavItem::new("Next", month_link(next_year, next_month)));
+
+    let body = req.render("calendar.html", Calendar { page, weeks })?;
+    Ok(body.into())
+}
+
+fn month_link(year: i32, month: u32) -> String {
+    format!("/archive/{}/{:02}", year, month)
+}
+
+async fn show_entry(req: AppRequest) -> tide::Result<tide::Response> {
+    let key = match req.session_key() {
+        Some(key) => key,
+        None => return login_redirect(),
+    };
+
+    let timestamp = entry_timestamp(&req)?;
+    let db = &req.state().db;
+    let entry = match db.get_entry(timestamp).await? {
+        Some(entry) => entry,
+        None => return not_found(&req),
+    };
+
+    let edits = db.revision_counts(timestamp, timestamp).await?.get(&timestamp).copied().unwrap_or(0);
+    let tags = tags::decrypt_tags(db, &key, timestamp, timestamp).await?.remove(&timestamp).unwrap_or_default();
+    let attachments = decrypt_attachments(db, &key, timestamp, timestamp).await?.remove(&timestamp).unwrap_or_default();
+    let backlinks = find_backlinks(db, &key, std::slice::from_ref(&entry), &req.state().markdown_opts).await?
+        .remove(&timestamp)
+        .unwrap_or_default();
+    let post = entry_to_post(entry, &req, &key)?;
+    let post = Post{ edits, tags, attachments, backlinks, ..post };
+
+    let mut page = req.page(post.timestamp.clone());
+    let (older, newer) = db.neighbors(timestamp).await?;
+    if let Some(older) = older {
+        page.previous.replace(NavItem::new("Previous", format!("/entry/{}", older)));
     }
-    if !posts.is_empty() {
-        page.next.replace(NavItem::new(
-            "Next",
-            format!("{}?offset={}&limit={}", req.url().path(), offset+limit, limit)
-        ));
+    if let Some(newer) = newer {
+        page.next.replace(NavItem::new("Next", format!("/entry/{}", newer)));
     }
 
-    let posts = Posts{page, posts};
-    let res: Response = match req.render("posts.html", posts) {
-        Ok(body) => body.into(),
-        Err(err) => err.into(),
+    let body = req.render("entry.html", EntryPage { page, post })?;
+    Ok(body.into())
+}
+
+async fn edit_entry(mut req: AppRequest) -> tide::Result<tide::Response> {
+    let key = match req.session_key() {
+        Some(key) => key,
+        None => return login_redirect(),
+    };
+
+    let timestamp = entry_timestamp(&req)?;
+    let entry = match req.state().db.get_entry(timestamp).await? {
+        Some(entry) => entry,
+        None => return not_found(&req),
+    };
+
+    let mut page = req.page("Edit");
+    let mut preview_html = String::new();
+    let mut post = key.decrypt_string(&entry.contents)?;
+    let mut tags = tags::decrypt_tags(&req.state().db, &key, timestamp, timestamp).await?
+        .remove(&timestamp)
+        .unwrap_or_default()
+        .join(", ");
+
+    if req.method() == tide::http::Method::Post {
+        let WritePost{post: edited, tags: edited_tags, preview, submit, ..} = req.body_form().await?;
+        post = edited;
+        tags = edited_tags;
+
+        if submit.is_some() {
+            let db = &req.state().db;
+            let public_key = &req.state().public_key;
+            let now = chrono::Utc::now().timestamp_millis();
+            let sealed = seal_tags(public_key, &post, &tags);
+            if !db.revise_tagged_entry(timestamp, public_key.encrypt(post.as_bytes()), &sealed, now).await? {
+                // Deleted since the page was loaded.
+                return not_found(&req);
+            }
+            page.flash_success("Correction saved. The previous version is in the history.");
+        } else if preview.is_some() {
+            preview_html = req.render_markdown(&post);
+        }
+    }
+
+    let body = req.render("edit.html", Edit {
+        page,
+        timestamp: format_time(entry.local_time()),
+        id: timestamp,
+        post,
+        tags,
+        preview_html,
+    })?;
+    Ok(body.into())
+}
+
+async fn entry_history(req: AppRequest) -> tide::Result<tide::Response> {
+    let key = match req.session_key() {
+        Some(key) => key,
+        None => return login_redirect(),
+    };
+
+    let timestamp = entry_timestamp(&req)?;
+    let db = &req.state().db;
+    let entry = match db.get_entry(timestamp).await? {
+        Some(entry) => entry,
+        None => return not_found(&req),
+    };
+
+    // Each version of the entry, oldest first, with the time it was written:
+    let mut versions = Vec::new();
+    let mut written = entry.timestamp_ms_utc;
+    for revision in db.get_revisions(timestamp).await? {
+        versions.push((written, key.decrypt_string(&revision.contents)?));
+        written = revision.replaced_ms_utc;
+    }
+    versions.push((written, key.decrypt_string(&entry.contents)?));
+
+    let offset = entry.local_time().offset().fix();
+    let mut history = Vec::new();
+    let mut previous: Option<&str> = None;
+    for (written, markdown) in &versions {
+        history.push(Version {
+            timestamp: format_time(offset.timestamp_millis(*written)),
+            html: req.render_markdown(markdown),
+            diff: previous.map(|p| diff_lines(p, markdown)).unwrap_or_default(),
+        });
+        previous = Some(markdown);
+    }
+    history.reverse();
+
+    let body = req.render("history.html", History {
+        page: req.page("History"),
+        id: timestamp,
+        versions: history,
+    })?;
+    Ok(body.into())
+}
+
+fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    use similar::{ChangeTag, TextDiff};
+
+    // Otherwise, adding a line after the last one shows as changing the last line:
+    let (old, new) = (format!("{}\n", old.trim_end_matches('\n')), format!("{}\n", new.trim_end_matches('\n')));
+
+    TextDiff::from_lines(&old, &new)
+        .iter_all_changes()
+        .map(|change| DiffLine {
+            change: match change.tag() {
+                ChangeTag::Delete => "delete",
+                ChangeTag::Insert => "insert",
+                ChangeTag::Equal => "equal",
+            },
+            text: change.value().trim_end_matches('\n').to_string(),
+        })
+        .collect()
+}
+
+async fn delete_entry(req: AppRequest) -> tide::Result<tide::Response> {
+    if !req.logged_in() {
+        return login_redirect();
+    }
+
+    let timestamp = entry_timestamp(&req)?;
+    if !req.state().db.delete_entry(timestamp).await? {
+        return not_found(&req);
+    }
+
+    let body = req.render("message.html", Message{
+        page: req.page("Deleted"),
+        message: "The post was deleted.".into(),
+    })?;
+    Ok(body.into())
+}
+
+/// The most we'll read from a request with attachments.
+const MAX_UPLOAD_BYTES: usize = 100 * 1024 * 1024;
+
+/// A file uploaded with a post, already encrypted with its own key.
+struct Upload {
+    name: String,
+    key: StreamKey,
+    contents: Vec<u8>,
+}
+
+/// A request body as a stream of chunks, for multer.
+fn body_stream(body: tide::Body) -> impl futures::Stream<Item = std::io::Result<Vec<u8>>> + Send + 'static {
+    use async_std::io::ReadExt as _;
+
+    futures::stream::unfold(body, |mut body| async move {
+        let mut chunk = vec![0; 64 * 1024];
+        match body.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(len) => {
+                chunk.truncate(len);
+                Some((Ok(chunk), body))
+            },
+            Err(e) => Some((Err(e), body)),
+        }
+    })
+}
+
+/// Read the write form, which is multipart if it has attachments.
+/// Files are encrypted as they arrive, so their plaintext is never all in memory at once.
+async fn read_write_form(req: &mut AppRequest) -> tide::Result<(WritePost, Vec<Upload>)> {
+    let boundary = match req.content_type() {
+        Some(mime) if mime.essence() == "multipart/form-data" => multer::parse_boundary(mime.to_string())
+            .map_err(|e| tide::Error::from_str(tide::StatusCode::BadRequest, e))?,
+        _ => return Ok((req.body_form().await?, Vec::new())),
+    };
+
+    let constraints = multer::Constraints::new()
+        .size_limit(multer::SizeLimit::new().whole_stream(MAX_UPLOAD_BYTES as u64));
+    let mut multipart = multer::Multipart::with_constraints(body_stream(req.take_body()), boundary, constraints);
+    let mut form = WritePost{ post: String::new(), tags: String::new(), draft: String::new(), preview: None, submit: None };
+    let mut uploads = Vec::new();
+    let bad_request = |e: multer::Error| {
+        let too_large = match &e {
+            multer::Error::StreamSizeExceeded{..} => true,
+            // Reading a field wraps errors from the stream:
+            multer::Error::StreamReadFailed(inner) => matches!(inner.downcast_ref(), Some(multer::Error::StreamSizeExceeded{..})),
+            _ => false,
+        };
+        if too_large {
+            tide::Error::from_str(
+                tide::StatusCode::PayloadTooLarge,
+                format!("Uploads are limited to {} MiB", MAX_UPLOAD_BYTES / 1024 / 1024),
+            )
+        } else {
+            tide::Error::from_str(tide::StatusCode::BadRequest, e)
+        }
+    };
+    while let Some(mut field) = multipart.next_field().await.map_err(bad_request)? {
+        let name = field.name().unwrap_or_default().to_string();
+        if let Some(file_name) = field.file_name().map(str::to_string) {
+            // An empty file input still sends a part, with no file name:
+            if file_name.is_empty() {
+                continue;
+            }
+            let key = StreamKey::generate();
+            let mut encryptor = key.encryptor();
+            while let Some(chunk) = field.chunk().await.map_err(bad_request)? {
+                encryptor.push(&chunk);
+            }
+            uploads.push(Upload { name: file_name, key, contents: encryptor.finish() });
+            continue;
+        }
+        let value = field.text().await.map_err(bad_request)?;
+        match name.as_str() {
+            "post" => form.post = value,
+            "tags" => form.tags = value,
+            "draft" => form.draft = value,
+            "preview" => form.preview = Some(value),
+            "submit" => form.submit = Some(value),
+            _ => {},
+        }
+    }
+    Ok((form, uploads))
+}
+
+async fn show_attachment(req: AppRequest) -> tide::Result<tide::Response> {
+    let key = match req.session_key() {
+        Some(key) => key,
+        None => return login_redirect(),
+    };
+
+    let id: i64 = req.param("id")?
+        .parse()
+        .map_err(|e| tide::Error::new(tide::StatusCode::BadRequest, e))?;
+    let attachment = match req.state().db.get_attachment(id).await? {
+        Some(attachment) => attachment,
+        None => return Ok(Response::builder(404).body("Not found").build()),
+    };
+
+    let name = key.decrypt_string(&attachment.name)?;
+    let file_key = StreamKey::from_bytes(&key.decrypt(&attachment.key)?)?;
+    let contents = file_key.decrypt(&attachment.contents)
+        .with_context(|| format!("Decrypting attachment {} of entry {}", id, attachment.entry_timestamp_ms_utc))?;
+
+    // RFC 5987 encoding, for names that aren't plain ASCII:
+    let file_name: String = name.bytes().map(|b| {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            (b as char).to_string()
+        } else {
+            format!("%{:02X}", b)
+        }
+    }).collect();
+    let response = Response::builder(200)
+        .body(contents)
+        .header("Content-Type", statics::content_type(&name).unwrap_or_else(|| "application/octet-stream".into()))
+        .header("Content-Disposition", format!("inline; filename*=UTF-8''{}", file_name))
+        // Uploaded HTML or SVG shouldn't be able to run scripts as this site:
+        .header("Content-Security-Policy", "sandbox")
+        .header("X-Content-Type-Options", "nosniff")
+        .build();
+    Ok(response)
+}
+
+/// Decrypted names of the attachments for each entry in a time range, by timestamp_ms_utc.
+async fn decrypt_attachments(db: &sqlx::SqlitePool, key: &SealedBoxPrivateKey, from_ms_utc: i64, to_ms_utc: i64) -> anyhow::Result<HashMap<i64, Vec<AttachmentLink>>> {
+    let mut links: HashMap<i64, Vec<AttachmentLink>> = HashMap::new();
+    for attachment in db.attachments_between(from_ms_utc, to_ms_utc).await? {
+        let name = key.decrypt_string(&attachment.name)?;
+        let image = mime_guess::from_path(&name).first().map(|m| m.type_() == "image").unwrap_or(false);
+        links.entry(attachment.entry_timestamp_ms_utc)
+            .or_default()
+            .push(AttachmentLink { id: attachment.id, name, image });
+    }
+    Ok(links)
+}
+
+/// Entries that link to any of `entries`, by the timestamp_ms_utc of the entry they link to.
+/// Only entries written or edited since the last lookup are decrypted, to index their links.
+async fn find_backlinks(db: &sqlx::SqlitePool, key: &SealedBoxPrivateKey, entries: &[Entry], options: &ComrakOptions) -> anyhow::Result<HashMap<i64, Vec<Backlink>>> {
+    if entries.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    links::index_links(db, key, options).await?;
+
+    // The ways to link to each entry:
+    let mut linked: HashMap<Vec<u8>, Vec<i64>> = HashMap::new();
+    for entry in entries {
+        let id = entry.timestamp_ms_utc;
+        linked.entry(links::Target::Entry(id).hash(key)).or_default().push(id);
+        linked.entry(links::Target::Day(entry.local_time().date().naive_local()).hash(key)).or_default().push(id);
+    }
+
+    let hashes: Vec<Vec<u8>> = linked.keys().cloned().collect();
+    let mut backlinks: HashMap<i64, Vec<Backlink>> = HashMap::new();
+    for link in db.links_to(&hashes).await? {
+        let source = link.entry_timestamp_ms_utc;
+        for &id in linked.get(&link.target_hash).into_iter().flatten().filter(|&&id| id != source) {
+            // Sources come in order, so a source linking to an entry twice is always the last one:
+            let found = backlinks.entry(id).or_default();
+            if found.last().map(|b| b.id) != Some(source) {
+                found.push(Backlink {
+                    id: source,
+                    timestamp: format_time(link.local_time()),
+                });
+            }
+        }
+    }
+    Ok(backlinks)
+}
+
+/// For VaultExt::get_posts(). Indexes any new tags first.
+async fn tag_hash(db: &sqlx::SqlitePool, key: &SealedBoxPrivateKey, tag: &str) -> anyhow::Result<Vec<u8>> {
+    db.index_tags(key).await?;
+    Ok(key.keyed_hash(tags::HASH_CONTEXT, tags::normalize(tag).as_bytes()))
+}
+
+/// Encrypt and save a new entry, written now. Returns its timestamp_ms_utc.
+async fn write_new_entry(db: &sqlx::SqlitePool, key: &SealedBoxPublicKey, post: &str, tags: &str, uploads: Vec<Upload>) -> anyhow::Result<i64> {
+    let now = chrono::Local::now();
+    let entry = Entry{
+        timestamp_ms_utc: now.timestamp_millis(),
+        offset_utc_mins: now.offset().fix().local_minus_utc() / 60,
+        contents: key.encrypt(post.as_bytes()),
+    };
+    let timestamp = entry.timestamp_ms_utc;
+    let attachments: Vec<db::Attachment> = uploads.into_iter().map(|upload| db::Attachment{
+        entry_timestamp_ms_utc: timestamp,
+        name: key.encrypt(upload.name.as_bytes()),
+        key: key.encrypt(upload.key.bytes()),
+        contents: upload.contents,
+    }).collect();
+    db.write_full_entry(entry, &seal_tags(key, post, tags), &attachments).await?;
+    Ok(timestamp)
+}
+
+/// Tags from a tag field, plus #hashtags in the post, sealed for VaultExt::write_full_entry().
+fn seal_tags(key: &SealedBoxPublicKey, post: &str, field: &str) -> Vec<Vec<u8>> {
+    let mut names = tags::parse_tag_field(field);
+    names.extend(tags::parse_hashtags(post));
+    names.iter().map(|name| key.encrypt(name.as_bytes())).collect()
+}
+
+/// Log in with the write password, if that's what `password` is.
+async fn write_login(req: &AppRequest, password: &str) -> Option<Response> {
+    let hash = req.state().access.write_password.as_ref()?;
+    if !verify_write_password(hash, password).await {
+        return None;
+    }
+    login_succeeded(req);
+    let mut res: Response = tide::Redirect::see_other("/").into();
+    res.insert_cookie(req.set_write_unlocked());
+    res.insert_ext(session::Changed);
+    Some(res)
+}
+
+async fn verify_write_password(hash: &str, password: &str) -> bool {
+    let (hash, password) = (hash.to_string(), password.to_string());
+    crypto::argon2(move || crypto::verify_password(&hash, &password)).await
+}
+
+/// Where a request came from, to throttle logins. None for a Unix socket.
+/// Behind a reverse proxy, this is the proxy, so only the global limit applies. See throttle.rs.
+fn peer_ip(req: &AppRequest) -> Option<IpAddr> {
+    req.peer_addr()?.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+/// Check before trying a password. Some(response) if this address has to wait.
+/// Otherwise the attempt counts as failed until login_succeeded.
+fn login_throttled(req: &AppRequest, private_key: bool) -> tide::Result<Option<Response>> {
+    let wait = match req.state().login_throttle.attempt(peer_ip(req), Instant::now()) {
+        Some(wait) => wait,
+        None => return Ok(None),
     };
+    let secs = wait.as_secs() + 1;
+    let mut login = LogIn::new(req, private_key);
+    login.page.flash_error(format!(
+        "Too many failed logins. Try again in {} second{}.", secs, if secs == 1 { "" } else { "s" }
+    ));
+    let mut res: Response = req.render("login.html", login)?.into();
+    res.set_status(tide::StatusCode::TooManyRequests);
+    res.insert_header("Retry-After", secs.to_string());
+    Ok(Some(res))
+}
+
+/// Records a failed attempt for `vault access`. login_throttled already counted it.
+async fn login_failed(req: &AppRequest) -> anyhow::Result<()> {
+    let ip = peer_ip(req);
+    req.state().db.record_login_failure(&db::LoginFailure{
+        timestamp_ms_utc: chrono::Utc::now().timestamp_millis(),
+        remote: ip.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".into()),
+        path: req.url().path().to_string(),
+    }).await
+}
+
+fn login_succeeded(req: &AppRequest) {
+    req.state().login_throttle.succeeded(peer_ip(req));
+}
+
+/// A 303, so that a form posted after the session expired is followed by a GET of the login
+/// page, rather than re-posted to /login.
+fn login_redirect() -> tide::Result<tide::Response> {
+    Ok(tide::Redirect::see_other("/login").into())
+}
+
+/// Entries are identified by their timestamp_ms_utc in URLs.
+fn entry_timestamp(req: &AppRequest) -> tide::Result<i64> {
+    req.param("timestamp")?
+        .parse()
+        .map_err(|e| tide::Error::new(tide::StatusCode::BadRequest, e))
+}
+
+fn not_found(req: &AppRequest) -> tide::Result<tide::Response> {
+    let body = req.render("message.html", Message{
+        page: req.page("Not Found"),
+        message: "No such post.".into(),
+    })?;
+    let mut res: Response = body.into();
+    res.set_status(tide::StatusCode::NotFound);
     Ok(res)
 }
 
 fn entry_to_post(entry: db::Entry, req: &AppRequest, key: &SealedBoxPrivateKey) -> anyhow::Result<Post> {
-    use chrono::TimeZone;
-
     let markdown = key.decrypt_string(&entry.contents)?;
     let html = req.render_markdown(&markdown);
 
-    let offset_secs = entry.offset_utc_mins * 60;
-    let timestamp = FixedOffset::east(offset_secs).timestamp_millis(entry.timestamp_ms_utc);
-    let timestamp = timestamp.format("%a %B %e, %Y - %T %z").to_string();
-
     Ok(Post{
+        id: entry.timestamp_ms_utc,
         html,
-        timestamp,
+        timestamp: format_time(entry.local_time()),
+        edits: 0,
+        tags: Vec::new(),
+        attachments: Vec::new(),
+        backlinks: Vec::new(),
     })
 }
 
+fn format_time(time: DateTime<FixedOffset>) -> String {
+    time.format("%a %B %e, %Y - %T %z").to_string()
+}
+
 #[derive(Serialize)]
 struct Write {
     page: Page,
     preview_html: String,
     post: String,
+    tags: String,
+    /// The token for autosaving this post as a draft.
+    draft: String,
+    /// A draft the user can restore.
+    saved_draft: Option<SavedDraft>,
+}
+
+#[derive(Serialize)]
+struct SavedDraft {
+    token: String,
+    saved: String,
+}
+
+/// The HTTP query params for the write page.
+#[derive(Deserialize)]
+struct WriteQuery {
+    /// Restore this draft.
+    draft: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DraftForm {
+    draft: String,
+    #[serde(default)]
+    post: String,
+    #[serde(default)]
+    tags: String,
+}
+
+#[derive(Serialize)]
+struct Edit {
+    page: Page,
+    id: i64,
+    timestamp: String,
+    preview_html: String,
+    post: String,
+    tags: String,
+}
+
+#[derive(Serialize)]
+struct History {
+    page: Page,
+    id: i64,
+    /// Newest first.
+    versions: Vec<Version>,
+}
+
+#[derive(Serialize)]
+struct Version {
+    timestamp: String,
+    html: String,
+    /// Changes from the previous version. Empty for the first version.
+    diff: Vec<DiffLine>,
+}
+
+#[derive(Serialize)]
+struct DiffLine {
+    /// One of: insert, delete, equal
+    change: &'static str,
+    text: String,
 }
 
 #[derive(Deserialize)]
 struct WritePost {
     post: String,
+    /// Separated by commas or spaces. See tags::parse_tag_field().
+    #[serde(default)]
+    tags: String,
+    /// The token for this post's draft, if any.
+    #[serde(default)]
+    draft: String,
     preview: Option<String>,
     submit: Option<String>,
 }
 
 /// The HTTP query params for the /read page.
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub(crate) struct ReadQuery {
-    pub(crate) offset: Option<usize>,
-
     pub(crate) limit: Option<usize>,
 
-    // TODO:
-    // #[serde(default)]
-    // chronological: bool,
+    /// Only show entries with this tag.
+    pub(crate) tag: Option<String>,
+
+    /// Oldest first, instead of newest first.
+    #[serde(default)]
+    pub(crate) chronological: bool,
+
+    /// Only entries written on or after this date, in the time zone each entry was written in.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub(crate) from: Option<NaiveDate>,
+
+    /// Only entries written on or before this date, in the time zone each entry was written in.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub(crate) to: Option<NaiveDate>,
+
+    /// Only entries older than this timestamp_ms_utc. Used for paging.
+    pub(crate) before: Option<i64>,
+
+    /// Only entries newer than this timestamp_ms_utc. Used for paging.
+    pub(crate) after: Option<i64>,
+}
+
+impl ReadQuery {
+    pub(crate) fn limit(&self) -> usize {
+        self.limit.unwrap_or(50)
+    }
+
+    /// True if paging toward the start of the list, from the first entry on a later page.
+    pub(crate) fn paging_back(&self) -> bool {
+        if self.chronological {
+            self.before.is_some() && self.after.is_none()
+        } else {
+            self.after.is_some() && self.before.is_none()
+        }
+    }
+}
+
+/// HTML forms submit empty fields as empty strings.
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    match value.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+/// The HTTP query params for the /on-this-day page.
+#[derive(Deserialize)]
+struct OnThisDayQuery {
+    /// Defaults to today, in the server's time zone.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    date: Option<NaiveDate>,
+}
+
+#[derive(Serialize)]
+struct OnThisDay {
+    page: Page,
+    date: String,
+    /// Newest first.
+    years: Vec<YearPosts>,
+}
+
+#[derive(Serialize)]
+struct YearPosts {
+    year: i32,
+    posts: Vec<Post>,
+}
+
+/// The HTTP query params for the /search page.
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+
+    /// Continue searching from entries older than this timestamp_ms_utc.
+    before: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct Search {
+    page: Page,
+    q: String,
+    results: Vec<Post>,
+}
+
+#[derive(Serialize)]
+struct Archive {
+    page: Page,
+    /// Years with entries, newest first.
+    years: Vec<ArchiveYear>,
+}
+
+#[derive(Serialize)]
+struct ArchiveYear {
+    year: i32,
+    total: i64,
+    /// All 12, including ones without entries.
+    months: Vec<ArchiveMonth>,
+}
+
+#[derive(Serialize)]
+struct ArchiveMonth {
+    name: String,
+    link: String,
+    count: i64,
+}
+
+#[derive(Serialize)]
+struct Calendar {
+    page: Page,
+    /// Monday first. None for days outside the month.
+    weeks: Vec<Vec<Option<CalendarDay>>>,
+}
+
+#[derive(Serialize)]
+struct CalendarDay {
+    day: u32,
+    count: i64,
+    /// To the day's posts, if it has any.
+    link: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -372,19 +1548,87 @@ struct Message {
 
 #[derive(Serialize)]
 pub(crate) struct Post {
+    /// The entry's timestamp_ms_utc, which identifies it.
+    pub(crate) id: i64,
     pub(crate) timestamp: String,
     pub(crate) html: String,
+    /// How many times the entry has been edited.
+    pub(crate) edits: i64,
+    pub(crate) tags: Vec<String>,
+    pub(crate) attachments: Vec<AttachmentLink>,
+    /// Entries that link to this one, oldest first.
+    pub(crate) backlinks: Vec<Backlink>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Backlink {
+    /// The linking entry's timestamp_ms_utc.
+    id: i64,
+    timestamp: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AttachmentLink {
+    id: i64,
+    name: String,
+    /// Show it inline.
+    image: bool,
+}
+
+#[derive(Serialize)]
+struct EntryPage {
+    page: Page,
+    post: Post,
+}
+
+#[derive(Serialize)]
+struct Tags {
+    page: Page,
+    /// Sorted by name.
+    tags: Vec<TagCount>,
+}
+
+#[derive(Serialize)]
+struct TagCount {
+    name: String,
+    /// How many entries have the tag.
+    count: i64,
 }
 
 #[derive(Serialize)]
 struct Posts {
     page: Page,
-    posts: Vec<Post>
+    posts: Vec<Post>,
+
+    // The current ReadQuery options, for the form:
+    tag: Option<String>,
+    chronological: bool,
+    from: String,
+    to: String,
 }
 
 #[derive(Serialize)]
 struct LogIn {
     page: Page,
+    /// Whether the private key can log in. Not in client-side mode.
+    private_key: bool,
+    write_password: bool,
+}
+
+impl LogIn {
+    fn new(req: &AppRequest, private_key: bool) -> Self {
+        Self {
+            page: req.page("Log In"),
+            private_key,
+            write_password: req.state().access.write_password.is_some(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SeedLogIn {
+    page: Page,
+    private_key: String,
 }
 
 #[derive(Deserialize)]
@@ -398,6 +1642,10 @@ struct Page {
     rel_path: Cow<'static, str>,
     title: Cow<'static, str>,
     nav: Vec<NavItem>,
+    /// For the "csrf" query parameter of every POST form's action. See Csrf.
+    csrf: String,
+    /// While logged in, or holding a client-side key, reload after this long idle, so the page locks.
+    idle_lock_secs: Option<u64>,
     flash: Option<Flash>,
     previous: Option<NavItem>,
     next: Option<NavItem>,
@@ -408,6 +1656,12 @@ impl Page {
         Self {
             rel_path: request.url().path().to_string().into(),
             nav: request.state().nav.clone(),
+            csrf: request.ext::<CsrfToken>().map(|t| t.0.clone()).unwrap_or_default(),
+            idle_lock_secs: if request.logged_in() || request.write_unlocked() {
+                Some(request.state().session_limits.idle.as_secs())
+            } else {
+                None
+            },
             title: title.into(),
             flash: None,
             next: None,
@@ -419,6 +1673,14 @@ impl Page {
 
         self.flash.replace(Flash { message: message.into(), flash_type: FlashType::SUCCESS });
     }
+
+    fn flash_warning(&mut self, message: impl Into<String>) {
+        self.flash.replace(Flash { message: message.into(), flash_type: FlashType::WARNING });
+    }
+
+    fn flash_error(&mut self, message: impl Into<String>) {
+        self.flash.replace(Flash { message: message.into(), flash_type: FlashType::ERROR });
+    }
 }
 
 #[derive(Serialize)]
@@ -428,7 +1690,7 @@ struct Flash {
     flash_type: FlashType,
 }
 
-#[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Serialize)]
 #[serde(rename_all = "lowercase")]
 enum FlashType {
@@ -438,16 +1700,30 @@ enum FlashType {
 }
 
 
+/// Who can do what, from `vault access`.
+#[derive(Clone)]
+struct Access {
+    /// An Argon2id hash. Without one, anyone can write.
+    write_password: Option<String>,
+    shutdown_needs_login: bool,
+}
+
 #[derive(Serialize, Clone)]
 pub(crate) struct NavItem {
     title: Cow<'static, str>,
     link: Cow<'static, str>,
     hidden: bool,
+    /// A button that POSTs to `link`, for actions.
+    post: bool,
 }
 
 impl NavItem {
     fn new(title: impl Into<Cow<'static, str>>, link: impl Into<Cow<'static, str>>) -> Self {
-        Self { title: title.into(), link: link.into(), hidden: false }
+        Self { title: title.into(), link: link.into(), hidden: false, post: false }
+    }
+
+    fn post(title: impl Into<Cow<'static, str>>, link: impl Into<Cow<'static, str>>) -> Self {
+        Self { post: true, .. Self::new(title, link) }
     }
 
     fn hidden(title: impl Into<Cow<'static, str>>, link: impl Into<Cow<'static, str>>) -> Self {
@@ -456,6 +1732,77 @@ impl NavItem {
 }
 
 
+/// Rejects POSTs that don't send back the CSRF token from the session's cookie, so other
+/// websites can't submit forms here. The API is exempt, since it uses bearer tokens instead.
+///
+/// Forms send the token in their action URL, like `/login?csrf=...`, so that checking it doesn't
+/// mean reading (maybe 100 MiB of) body first. Referrer-Policy keeps those URLs on this site.
+struct Csrf {}
+
+#[derive(Clone)]
+struct CsrfToken(String);
+
+#[async_trait]
+impl tide::Middleware<AppState> for Csrf {
+    async fn handle(&self, mut req: AppRequest, next: tide::Next<'_, AppState>) -> tide::Result<Response> {
+        let existing = req.cookie(CSRF_COOKIE)
+            .and_then(|cookie| req.decrypt_bytes(&cookie).ok().flatten())
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        let is_new = existing.is_none();
+        let token = existing.unwrap_or_else(random_token);
+
+        let checked = req.method().is_safe() || req.url().path().starts_with("/api/");
+        if !checked {
+            let submitted = submitted_csrf_token(&req).unwrap_or_default();
+            if is_new || !sodiumoxide::utils::memcmp(submitted.as_bytes(), token.as_bytes()) {
+                // Starts a new session, so reloading the form fixes it.
+                req.set_ext(CsrfToken(token.clone()));
+                let body = req.render("message.html", Message{
+                    page: req.page("Form Expired"),
+                    message: "That form expired, or came from another website. Go back, reload the page, and try again.".into(),
+                })?;
+                let mut res: Response = body.into();
+                res.set_status(tide::StatusCode::Forbidden);
+                res.insert_header("Referrer-Policy", "same-origin");
+                if is_new {
+                    res.insert_cookie(csrf_cookie(&req, &token));
+                }
+                return Ok(res);
+            }
+        }
+
+        req.set_ext(CsrfToken(token.clone()));
+        let cookie = if is_new { Some(csrf_cookie(&req, &token)) } else { None };
+        let mut res = next.run(req).await;
+        res.insert_header("Referrer-Policy", "same-origin");
+        if let Some(cookie) = cookie {
+            res.insert_cookie(cookie);
+        }
+        Ok(res)
+    }
+}
+
+fn csrf_cookie(req: &AppRequest, token: &str) -> Cookie<'static> {
+    let mut cookie = Cookie::build(CSRF_COOKIE, "")
+        .path("/")
+        .http_only(true)
+        .same_site(tide::http::cookies::SameSite::Strict)
+        .secure(req.state().tls)
+        .finish();
+    req.encrypt_bytes(&mut cookie, token.as_bytes());
+    cookie
+}
+
+/// From an X-CSRF-Token header, or a "csrf" query parameter.
+fn submitted_csrf_token(req: &AppRequest) -> Option<String> {
+    if let Some(header) = req.header("X-CSRF-Token") {
+        return Some(header.last().as_str().to_string());
+    }
+    req.url().query_pairs()
+        .find(|(name, _)| name == "csrf")
+        .map(|(_, value)| value.into_owned())
+}
+
 // See: https://github.com/http-rs/tide/issues/854
 struct NoStore {}
 
@@ -466,7 +1813,7 @@ impl <State: Clone + Send + Sync + 'static> tide::Middleware<State> for NoStore
         use tide::http::cache::{CacheControl, CacheDirective};
         let mut response = next.run(req).await;
 
-        if let None = response.header("Cache-Control") {
+        if response.header("Cache-Control").is_none() {
             let mut header = CacheControl::new();
             header.push(CacheDirective::NoStore);
             header.push(CacheDirective::MaxAge(Duration::from_secs(0)));
@@ -0,0 +1,169 @@
+//! A JSON API under /api/v1, for scripts and other clients.
+//!
+//! Writing only needs the public key, so anyone who can reach the server can post, same as the
+//! write page. Unless there's a write password: then send it, or the API token, as a bearer token.
+//! Reading needs a bearer token from `vault api-token`, which unlocks the private key.
+
+use std::time::Instant;
+
+use serde::{Serialize, Deserialize};
+use tide::{Body, Response, StatusCode};
+
+use crate::{crypto::SealedBoxPrivateKey, db::{Entry, VaultExt}, export::ExportedEntry, tags};
+use super::{
+    AppRequest, AppState, ReadQuery, entry_timestamp, login_failed, login_succeeded, peer_ip, tag_hash,
+    verify_write_password, write_new_entry,
+};
+
+pub(super) fn routes(mut api: tide::Route<'_, AppState>) {
+    api.at("/public-key").get(public_key);
+    api.at("/entries")
+    .get(list_entries)
+    .post(write_entry);
+    api.at("/entries/:timestamp").get(get_entry);
+}
+
+async fn public_key(req: AppRequest) -> tide::Result<Response> {
+    json(StatusCode::Ok, &PublicKey{ public_key: req.state().public_key.to_string() })
+}
+
+async fn write_entry(mut req: AppRequest) -> tide::Result<Response> {
+    if let Some(hash) = &req.state().access.write_password {
+        let token = match bearer_token(&req) {
+            Some(token) => token,
+            None => return error(StatusCode::Unauthorized, "Writing needs the write password or API token."),
+        };
+        // The write password works here too, so guessing it is throttled like /login.
+        if req.state().login_throttle.attempt(peer_ip(&req), Instant::now()).is_some() {
+            return error(StatusCode::TooManyRequests, "Too many failed logins. Try again later.");
+        }
+        if verify_write_password(hash, token).await || req.state().db.unlock_api_token(token).await?.is_some() {
+            login_succeeded(&req);
+        } else {
+            login_failed(&req).await?;
+            return error(StatusCode::Unauthorized, "Writing needs the write password or API token.");
+        }
+    }
+    // Other websites can only send JSON after a CORS preflight, which we never allow.
+    // So this is what keeps the API exempt from CSRF tokens.
+    if req.content_type().map(|mime| mime.essence() != "application/json").unwrap_or(true) {
+        return error(StatusCode::UnsupportedMediaType, "Send entries as application/json.");
+    }
+    let new: NewEntry = match req.body_json().await {
+        Ok(new) => new,
+        Err(e) => return error(StatusCode::BadRequest, &e.to_string()),
+    };
+    if new.contents.trim().is_empty() {
+        return error(StatusCode::BadRequest, "An entry needs contents.");
+    }
+
+    let state = req.state();
+    let timestamp_ms_utc = write_new_entry(&state.db, &state.public_key, &new.contents, &new.tags.join(" "), Vec::new()).await?;
+    json(StatusCode::Created, &Written{ timestamp_ms_utc })
+}
+
+async fn list_entries(req: AppRequest) -> tide::Result<Response> {
+    let key = match bearer_key(&req).await? {
+        Ok(key) => key,
+        Err(res) => return Ok(res),
+    };
+    let query: ReadQuery = req.query()?;
+
+    let db = &req.state().db;
+    let tag_hash = match query.tag.as_deref().filter(|t| !t.trim().is_empty()) {
+        Some(tag) => Some(tag_hash(db, &key, tag).await?),
+        None => None,
+    };
+    let entries = db.get_posts(&query, tag_hash.as_deref()).await?;
+    let entries = to_api_entries(&req, &key, entries).await?;
+    json(StatusCode::Ok, &Entries{ entries })
+}
+
+async fn get_entry(req: AppRequest) -> tide::Result<Response> {
+    let key = match bearer_key(&req).await? {
+        Ok(key) => key,
+        Err(res) => return Ok(res),
+    };
+    let timestamp = entry_timestamp(&req)?;
+    let entry = match req.state().db.get_entry(timestamp).await? {
+        Some(entry) => entry,
+        None => return error(StatusCode::NotFound, "No such entry."),
+    };
+    let mut entries = to_api_entries(&req, &key, vec![entry]).await?;
+    json(StatusCode::Ok, &entries.remove(0))
+}
+
+async fn to_api_entries(req: &AppRequest, key: &SealedBoxPrivateKey, entries: Vec<Entry>) -> anyhow::Result<Vec<ExportedEntry>> {
+    let timestamps = entries.iter().map(|e| e.timestamp_ms_utc);
+    let mut entry_tags = match (timestamps.clone().min(), timestamps.max()) {
+        (Some(from), Some(to)) => tags::decrypt_tags(&req.state().db, key, from, to).await?,
+        _ => Default::default(),
+    };
+    entries.iter().map(|e| {
+        Ok(ExportedEntry{
+            tags: entry_tags.remove(&e.timestamp_ms_utc).unwrap_or_default(),
+            ..ExportedEntry::decrypt(e, key)?
+        })
+    }).collect()
+}
+
+/// The private key unlocked by an `Authorization: Bearer` token.
+/// Err is a response to send back instead.
+async fn bearer_key(req: &AppRequest) -> tide::Result<Result<SealedBoxPrivateKey, Response>> {
+    let token = match bearer_token(req) {
+        Some(token) => token,
+        None => return Ok(Err(error(StatusCode::Unauthorized, "Missing bearer token.")?)),
+    };
+    match req.state().db.unlock_api_token(token).await? {
+        Some(key) => Ok(Ok(key)),
+        None => Ok(Err(error(StatusCode::Unauthorized, "Invalid bearer token.")?)),
+    }
+}
+
+fn bearer_token(req: &AppRequest) -> Option<&str> {
+    req.header("Authorization")
+        .and_then(|h| h.last().as_str().strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> tide::Result<Response> {
+    let mut res = Response::new(status);
+    res.set_body(Body::from_json(value)?);
+    Ok(res)
+}
+
+fn error(status: StatusCode, message: &str) -> tide::Result<Response> {
+    let mut res = json(status, &Error{ error: message })?;
+    if status == StatusCode::Unauthorized {
+        res.insert_header("WWW-Authenticate", "Bearer");
+    }
+    Ok(res)
+}
+
+#[derive(Deserialize)]
+struct NewEntry {
+    contents: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Written {
+    timestamp_ms_utc: i64,
+}
+
+#[derive(Serialize)]
+struct PublicKey {
+    public_key: String,
+}
+
+#[derive(Serialize)]
+struct Entries {
+    entries: Vec<ExportedEntry>,
+}
+
+#[derive(Serialize)]
+struct Error<'a> {
+    error: &'a str,
+}
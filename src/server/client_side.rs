@@ -0,0 +1,178 @@
+//! Routes for `--client-side` mode, where the browser does all encryption and decryption.
+//!
+//! The server only stores and returns ciphertext from the `entry` table. The private key stays in
+//! the browser (see static/sealedbox.js), so anything that needs plaintext on the server, like
+//! search, tags, and rendering Markdown, isn't available.
+
+use serde::{Serialize, Deserialize};
+use sodiumoxide::base64::{self, Variant};
+use tide::{Response, StatusCode};
+
+use crate::db::{Entry, VaultExt};
+use super::{
+    AppRequest, AppState, LogIn, LogInForm, NavItem, Page, ReadQuery, RequestExt,
+    format_time, login_failed, login_redirect, login_throttled, paging_links, write_login,
+};
+
+/// libsodium's crypto_box_SEALBYTES: an ephemeral public key and a MAC.
+const SEAL_BYTES: usize = 48;
+
+pub(super) fn nav() -> Vec<NavItem> {
+    vec![
+        NavItem::new("Write", "/"),
+        NavItem::hidden("Log In", "/login"),
+        NavItem::new("Unlock", "/unlock"),
+        NavItem::new("Read", "/read"),
+        NavItem::post("Lock", "/logout"),
+        NavItem::new("Shutdown", "/shutdown"),
+    ]
+}
+
+/// A page that runs the idle timer whether or not the server has a session, since the browser
+/// may be holding the private key. See base.html.
+fn key_page(req: &AppRequest, title: &'static str) -> Page {
+    let mut page = req.page(title);
+    page.idle_lock_secs = Some(req.state().session_limits.idle.as_secs());
+    page
+}
+
+pub(super) fn routes(app: &mut tide::Server<AppState>) {
+    app.at("/")
+    .get(write_page)
+    .post(write_entry);
+
+    app.at("/unlock")
+    .get(unlock_page);
+
+    // Only for the write password. The private key never goes to the server.
+    app.at("/login")
+    .get(login_page)
+    .post(login);
+
+    app.at("/read")
+    .get(read_posts);
+}
+
+async fn write_page(req: AppRequest) -> tide::Result<Response> {
+    if !req.can_write() {
+        return login_redirect();
+    }
+    let page = key_page(&req, "Write");
+    render_write(&req, page)
+}
+
+async fn write_entry(mut req: AppRequest) -> tide::Result<Response> {
+    if !req.can_write() {
+        return login_redirect();
+    }
+    let form: SealedForm = req.body_form().await?;
+    let contents = match base64::decode(form.sealed.trim(), Variant::Original) {
+        Ok(contents) if contents.len() > SEAL_BYTES => contents,
+        _ => {
+            let mut page = key_page(&req, "Write");
+            page.flash_error("That post wasn't encrypted. Is JavaScript enabled?");
+            let mut res = render_write(&req, page)?;
+            res.set_status(StatusCode::BadRequest);
+            return Ok(res);
+        },
+    };
+
+    let now = chrono::Local::now();
+    req.state().db.write_entry(Entry{
+        timestamp_ms_utc: now.timestamp_millis(),
+        offset_utc_mins: now.offset().local_minus_utc() / 60,
+        contents,
+    }).await?;
+
+    let mut page = key_page(&req, "Write");
+    page.flash_success("Post saved.");
+    render_write(&req, page)
+}
+
+fn render_write(req: &AppRequest, page: Page) -> tide::Result<Response> {
+    let body = req.render("client_write.html", KeyPage{
+        page,
+        public_key: req.state().public_key.to_string(),
+    })?;
+    Ok(body.into())
+}
+
+async fn unlock_page(req: AppRequest) -> tide::Result<Response> {
+    let body = req.render("unlock.html", KeyPage{
+        page: key_page(&req, "Unlock"),
+        public_key: req.state().public_key.to_string(),
+    })?;
+    Ok(body.into())
+}
+
+async fn login_page(req: AppRequest) -> tide::Result<Response> {
+    let body = req.render("login.html", LogIn::new(&req, false))?;
+    Ok(body.into())
+}
+
+async fn login(mut req: AppRequest) -> tide::Result<Response> {
+    if let Some(res) = login_throttled(&req, false)? {
+        return Ok(res);
+    }
+    let form: LogInForm = req.body_form().await?;
+    if let Some(res) = write_login(&req, &form.secret).await {
+        return Ok(res);
+    }
+    login_failed(&req).await?;
+    let mut login = LogIn::new(&req, false);
+    login.page.flash_error("Incorrect write password.");
+    let body = req.render("login.html", login)?;
+    Ok(body.into())
+}
+
+async fn read_posts(req: AppRequest) -> tide::Result<Response> {
+    let query: ReadQuery = req.query()?;
+    let entries = req.state().db.get_posts(&query, None).await?;
+    let ids: Vec<i64> = entries.iter().map(|e| e.timestamp_ms_utc).collect();
+
+    let mut page = key_page(&req, "Read Posts");
+    paging_links(&req, &query, None, &ids, &mut page);
+
+    let posts = entries.iter().map(|e| SealedPost{
+        timestamp: format_time(e.local_time()),
+        sealed: base64::encode(&e.contents, Variant::Original),
+    }).collect();
+    let body = req.render("client_read.html", SealedPosts{
+        page,
+        posts,
+        chronological: query.chronological,
+        from: query.from.map(|d| d.to_string()).unwrap_or_default(),
+        to: query.to.map(|d| d.to_string()).unwrap_or_default(),
+    })?;
+    Ok(body.into())
+}
+
+#[derive(Deserialize)]
+struct SealedForm {
+    /// Base64 sealed box. The plaintext textarea has no name, so it's never sent.
+    #[serde(default)]
+    sealed: String,
+}
+
+#[derive(Serialize)]
+struct KeyPage {
+    page: Page,
+    public_key: String,
+}
+
+#[derive(Serialize)]
+struct SealedPost {
+    timestamp: String,
+    sealed: String,
+}
+
+#[derive(Serialize)]
+struct SealedPosts {
+    page: Page,
+    posts: Vec<SealedPost>,
+
+    // The current ReadQuery options, for the form:
+    chronological: bool,
+    from: String,
+    to: String,
+}
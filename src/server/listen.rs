@@ -0,0 +1,273 @@
+//! Where `vault serve` listens: a TCP address, with or without TLS, or a Unix domain socket.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    fs::File,
+    io::BufReader,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, bail};
+use async_std::{io, net::{TcpListener, TcpStream}, prelude::*, task};
+use futures_rustls::{TlsAcceptor, rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig}, server::TlsStream};
+use tide::{Server, listener::{ListenInfo, Listener, ToListener}};
+
+use crate::OpenOpts;
+
+pub(super) enum Listen {
+    Tcp(SocketAddr),
+    Tls(SocketAddr, Arc<ServerConfig>),
+    Unix(PathBuf),
+}
+
+impl Listen {
+    pub(super) fn from_opts(opts: &OpenOpts) -> anyhow::Result<Self> {
+        if let Some(path) = &opts.unix_socket {
+            if opts.tls_cert.is_some() {
+                bail!("--tls-cert doesn't apply to a Unix socket. Terminate TLS in your reverse proxy.");
+            }
+            return Ok(Listen::Unix(path.clone()));
+        }
+
+        let addr = SocketAddr::new(opts.bind, opts.port);
+        let tls = match (&opts.tls_cert, &opts.tls_key) {
+            (Some(cert), Some(key)) => Some(tls_config(cert, key)?),
+            (None, None) => None,
+            _ => bail!("--tls-cert and --tls-key must be used together."),
+        };
+        check_bind(opts.bind, tls.is_some(), opts.insecure_bind)?;
+
+        Ok(match tls {
+            Some(config) => Listen::Tls(addr, Arc::new(config)),
+            None => Listen::Tcp(addr),
+        })
+    }
+
+    pub(super) fn is_tls(&self) -> bool {
+        matches!(self, Listen::Tls(..))
+    }
+
+    /// Where to point a browser, if anywhere.
+    pub(super) fn url(&self) -> Option<String> {
+        let (scheme, addr) = match self {
+            Listen::Tcp(addr) => ("http", addr),
+            Listen::Tls(addr, _) => ("https", addr),
+            Listen::Unix(_) => return None,
+        };
+        let mut addr = *addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::from([127, 0, 0, 1]),
+                IpAddr::V6(_) => IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+            });
+        }
+        Some(format!("{}://{}/", scheme, addr))
+    }
+
+    pub(super) async fn listen<State>(self, app: Server<State>) -> io::Result<()>
+    where State: Clone + Send + Sync + 'static
+    {
+        match self {
+            Listen::Tcp(addr) => app.listen(addr).await,
+            Listen::Tls(addr, config) => app.listen(TlsListener::new(addr, config)).await,
+            Listen::Unix(path) => listen_unix(app, path).await,
+        }
+    }
+}
+
+impl Display for Listen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Unix(path) => write!(f, "unix:{}", path.to_string_lossy()),
+            _ => write!(f, "{}", self.url().unwrap_or_default()),
+        }
+    }
+}
+
+/// Cookies and keys would cross the network in the clear, so only allow that on purpose.
+fn check_bind(ip: IpAddr, tls: bool, insecure: bool) -> anyhow::Result<()> {
+    if ip.is_loopback() || tls || insecure {
+        return Ok(());
+    }
+    bail!(
+        "Refusing to listen on {} without TLS. Use --tls-cert and --tls-key, \
+        or --insecure-bind if something else (like a reverse proxy) provides TLS.",
+        ip
+    )
+}
+
+fn tls_config(cert: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = pem_items(cert)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        bail!("No certificates in {}", cert.to_string_lossy());
+    }
+
+    let key_der = pem_items(key)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der) | rustls_pemfile::Item::RSAKey(der) => Some(der),
+            _ => None,
+        })
+        .with_context(|| format!("No private key in {}", key.to_string_lossy()))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, PrivateKey(key_der))
+        .with_context(|| format!("Using the key in {}", key.to_string_lossy()))?;
+    Ok(config)
+}
+
+fn pem_items(path: &Path) -> anyhow::Result<Vec<rustls_pemfile::Item>> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.to_string_lossy()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Reading {}", path.to_string_lossy()))
+}
+
+#[cfg(unix)]
+async fn listen_unix<State>(app: Server<State>, path: PathBuf) -> io::Result<()>
+where State: Clone + Send + Sync + 'static
+{
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    // A socket nothing answers on was left behind by a previous run. Binding fails if it's still there.
+    let is_socket = std::fs::metadata(&path).map(|m| m.file_type().is_socket()).unwrap_or(false);
+    if is_socket {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "Another server is already listening on that socket"));
+        }
+        std::fs::remove_file(&path)?;
+    }
+    app.listen(path).await
+}
+
+#[cfg(not(unix))]
+async fn listen_unix<State>(_app: Server<State>, _path: PathBuf) -> io::Result<()>
+where State: Clone + Send + Sync + 'static
+{
+    Err(io::Error::new(io::ErrorKind::Other, "Unix sockets aren't supported on this platform"))
+}
+
+/// Like tide's TCP listener, with a TLS handshake before handing each connection to HTTP.
+struct TlsListener<State> {
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    listener: Option<TcpListener>,
+    server: Option<Server<State>>,
+}
+
+impl<State> TlsListener<State> {
+    fn new(addr: SocketAddr, config: Arc<ServerConfig>) -> Self {
+        Self { addr, acceptor: config.into(), listener: None, server: None }
+    }
+}
+
+#[async_trait::async_trait]
+impl<State> Listener<State> for TlsListener<State>
+where State: Clone + Send + Sync + 'static
+{
+    async fn bind(&mut self, server: Server<State>) -> io::Result<()> {
+        self.server = Some(server);
+        self.listener = Some(TcpListener::bind(self.addr).await?);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let server = self.server.take().expect("`bind` must be called before `accept`");
+        let listener = self.listener.take().expect("`bind` must be called before `accept`");
+
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    tide::log::error!("Error accepting a connection: {}", error);
+                    continue;
+                },
+            };
+            let server = server.clone();
+            let acceptor = self.acceptor.clone();
+            task::spawn(async move {
+                let local_addr = stream.local_addr().ok();
+                let peer_addr = stream.peer_addr().ok();
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => SharedStream(Arc::new(Mutex::new(stream))),
+                    Err(error) => {
+                        tide::log::error!("TLS handshake failed: {}", error);
+                        return;
+                    },
+                };
+                let served = async_h1::accept(stream, |mut req| async {
+                    req.set_local_addr(local_addr);
+                    req.set_peer_addr(peer_addr);
+                    server.respond(req).await
+                });
+                if let Err(error) = served.await {
+                    tide::log::error!("async-h1 error: {}", error);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        vec![ListenInfo::new(self.to_string(), "tcp".into(), true)]
+    }
+}
+
+impl<State> ToListener<State> for TlsListener<State>
+where State: Clone + Send + Sync + 'static
+{
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(self)
+    }
+}
+
+impl<State> Debug for TlsListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsListener").field("addr", &self.addr).finish()
+    }
+}
+
+impl<State> Display for TlsListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "https://{}", self.addr)
+    }
+}
+
+/// async-h1 needs a stream it can clone, to read requests while writing responses.
+#[derive(Clone)]
+struct SharedStream(Arc<Mutex<TlsStream<TcpStream>>>);
+
+impl io::Read for SharedStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().expect("TLS stream lock")).poll_read(cx, buf)
+    }
+}
+
+impl io::Write for SharedStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().expect("TLS stream lock")).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().expect("TLS stream lock")).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().expect("TLS stream lock")).poll_close(cx)
+    }
+}
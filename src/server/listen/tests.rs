@@ -0,0 +1,29 @@
+use std::net::{IpAddr, SocketAddr};
+
+use super::{Listen, check_bind};
+
+#[test]
+fn test_check_bind() {
+    let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+    let loopback_v6: IpAddr = "::1".parse().unwrap();
+    let lan: IpAddr = "192.168.1.10".parse().unwrap();
+    let any: IpAddr = "0.0.0.0".parse().unwrap();
+
+    assert!(check_bind(loopback, false, false).is_ok());
+    assert!(check_bind(loopback_v6, false, false).is_ok());
+    assert!(check_bind(lan, false, false).is_err());
+    assert!(check_bind(any, false, false).is_err());
+    assert!(check_bind(lan, true, false).is_ok());
+    assert!(check_bind(any, false, true).is_ok());
+}
+
+#[test]
+fn test_url() {
+    let addr: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+    assert_eq!(Listen::Tcp(addr).url().unwrap(), "http://127.0.0.1:8080/");
+    let addr: SocketAddr = "[::]:8080".parse().unwrap();
+    assert_eq!(Listen::Tcp(addr).url().unwrap(), "http://[::1]:8080/");
+    let addr: SocketAddr = "192.168.1.10:443".parse().unwrap();
+    assert_eq!(Listen::Tcp(addr).url().unwrap(), "http://192.168.1.10:443/");
+    assert_eq!(Listen::Unix("/run/vault.sock".into()).url(), None);
+}
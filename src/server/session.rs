@@ -0,0 +1,182 @@
+//! How long logins last. The `login` and `write` cookies are sessions: their encrypted payload
+//! starts with when the session began and when it was last used. A session ends after a fixed
+//! lifetime, or sooner if it sits idle, so a journal left open on a shared machine locks itself.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    convert::TryInto,
+    fs,
+    io::Write as _,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use tide::{Response, http::Cookie};
+
+use crate::{crypto::SecretBox, db::{self, VaultExt as _}};
+use super::{AppRequest, AppState, PRIV_KEY_COOKIE, RequestExt, WRITE_COOKIE};
+
+/// Two timestamps, in seconds since the Unix epoch.
+const HEADER_BYTES: usize = 16;
+
+/// Don't re-send cookies on every request just to bump `last_seen`.
+const TOUCH_SECS: u64 = 60;
+
+#[derive(Clone, Copy)]
+pub(super) struct Limits {
+    pub(super) lifetime: Duration,
+    pub(super) idle: Duration,
+}
+
+pub(super) struct Session {
+    started: u64,
+    last_seen: u64,
+    pub(super) data: Vec<u8>,
+}
+
+impl Session {
+    pub(super) fn new(data: &[u8], now: u64) -> Self {
+        Self { started: now, last_seen: now, data: data.to_vec() }
+    }
+
+    pub(super) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_BYTES {
+            return None;
+        }
+        Some(Self {
+            started: u64::from_be_bytes(bytes[0..8].try_into().ok()?),
+            last_seen: u64::from_be_bytes(bytes[8..16].try_into().ok()?),
+            data: bytes[HEADER_BYTES..].to_vec(),
+        })
+    }
+
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_BYTES + self.data.len());
+        bytes.extend_from_slice(&self.started.to_be_bytes());
+        bytes.extend_from_slice(&self.last_seen.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub(super) fn is_live(&self, limits: &Limits, now: u64) -> bool {
+        now.saturating_sub(self.started) < limits.lifetime.as_secs()
+            && now.saturating_sub(self.last_seen) < limits.idle.as_secs()
+    }
+
+    /// Until the lifetime runs out. Cookies get this as their Max-Age, so the browser drops them
+    /// even if the server never sees them again.
+    pub(super) fn remaining(&self, limits: &Limits, now: u64) -> Duration {
+        Duration::from_secs((self.started + limits.lifetime.as_secs()).saturating_sub(now))
+    }
+
+    /// The session with `last_seen` bumped, if it's due.
+    fn touched(&self, now: u64) -> Option<Self> {
+        if now.saturating_sub(self.last_seen) < TOUCH_SECS {
+            return None;
+        }
+        Some(Self { started: self.started, last_seen: now, data: self.data.clone() })
+    }
+}
+
+pub(super) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The key for cookies, from `file`, so sessions outlive a restart.
+/// Makes a new one if the file is missing, or was for another vault.
+///
+/// The key in the file is wrapped with SETTING_SESSION_WRAP_KEY, which is stored in the vault in
+/// the clear. So someone with both the vault and the file can decrypt an old `login` cookie, which
+/// holds the private key. Delete the setting to invalidate the file.
+pub(super) async fn load_key(db: &sqlx::SqlitePool, file: &Path) -> anyhow::Result<SecretBox> {
+    let wrapper = match db.read_setting(db::SETTING_SESSION_WRAP_KEY).await? {
+        Some(key) => SecretBox::from_bytes(&bs58::decode(key).into_vec()?)?,
+        None => {
+            let key = SecretBox::generate();
+            db.write_setting(db::SETTING_SESSION_WRAP_KEY, &bs58::encode(key.bytes()).into_string()).await?;
+            key
+        },
+    };
+
+    if file.exists() {
+        let contents = fs::read_to_string(file).with_context(|| format!("Reading {}", file.to_string_lossy()))?;
+        if let Some(key) = unwrap_key(&wrapper, &contents) {
+            return Ok(key);
+        }
+        println!("Session key in {} is for another vault, or was reset. Everyone will need to log in again.", file.to_string_lossy());
+    }
+
+    let key = SecretBox::generate();
+    write_private(file, &wrap_key(&wrapper, &key))
+        .with_context(|| format!("Writing {}", file.to_string_lossy()))?;
+    Ok(key)
+}
+
+fn wrap_key(wrapper: &SecretBox, key: &SecretBox) -> String {
+    bs58::encode(wrapper.encrypt(key.bytes())).into_string()
+}
+
+fn unwrap_key(wrapper: &SecretBox, contents: &str) -> Option<SecretBox> {
+    let wrapped = bs58::decode(contents.trim()).into_vec().ok()?;
+    SecretBox::from_bytes(&wrapper.decrypt(&wrapped).ok()?).ok()
+}
+
+/// Only readable by the user running the server.
+fn write_private(file: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt as _;
+        options.mode(0o600);
+    }
+    options.open(file)?.write_all(contents.as_bytes())
+}
+
+/// For `remove_cookie()`.
+pub(super) fn ended(name: &'static str) -> Cookie<'static> {
+    Cookie::build(name, "").path("/").finish()
+}
+
+/// Put on a response that logs in or out, so `Sessions` leaves its cookies alone.
+#[derive(Clone)]
+pub(super) struct Changed;
+
+/// Keeps idle sessions alive while they're used, and deletes cookies for ones that ended.
+pub(super) struct Sessions {}
+
+#[async_trait]
+impl tide::Middleware<AppState> for Sessions {
+    async fn handle(&self, req: AppRequest, next: tide::Next<'_, AppState>) -> tide::Result<Response> {
+        let now = now();
+        let mut touched = Vec::new();
+        let mut ended_names = Vec::new();
+        for &name in &[PRIV_KEY_COOKIE, WRITE_COOKIE] {
+            if req.cookie(name).is_none() {
+                continue;
+            }
+            match req.login_session(name) {
+                Ok(Some(session)) => touched.extend(
+                    session.touched(now).map(|session| req.session_cookie(name, &session))
+                ),
+                // Expired, or from a previous run of the server:
+                _ => ended_names.push(name),
+            }
+        }
+
+        let mut res = next.run(req).await;
+        if res.ext::<Changed>().is_none() {
+            for cookie in touched {
+                res.insert_cookie(cookie);
+            }
+            for name in ended_names {
+                res.remove_cookie(ended(name));
+            }
+        }
+        Ok(res)
+    }
+}
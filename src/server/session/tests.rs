@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use crate::{crypto::SecretBox, db::{self, VaultExt as _}};
+use super::{Limits, Session, load_key, unwrap_key, wrap_key};
+
+#[test]
+fn test_session_limits() {
+    let limits = Limits{ lifetime: Duration::from_secs(3600), idle: Duration::from_secs(600) };
+    let session = Session::new(b"key", 1000);
+    assert!(session.is_live(&limits, 1000));
+    assert!(session.is_live(&limits, 1599));
+    assert!(!session.is_live(&limits, 1600));
+    assert_eq!(session.remaining(&limits, 1000), Duration::from_secs(3600));
+
+    // Using it keeps it alive, but only until the lifetime runs out:
+    let session = session.touched(1500).unwrap();
+    assert!(session.touched(1530).is_none());
+    assert!(session.is_live(&limits, 2000));
+    let mut session = session;
+    for now in (2000..4600).step_by(500) {
+        session = session.touched(now).unwrap();
+    }
+    assert!(!session.is_live(&limits, 4600));
+    assert_eq!(session.remaining(&limits, 4000), Duration::from_secs(600));
+    assert_eq!(session.remaining(&limits, 5000), Duration::from_secs(0));
+
+    let bytes = session.to_bytes();
+    let session = Session::from_bytes(&bytes).unwrap();
+    assert_eq!(session.data, b"key");
+    assert!(Session::from_bytes(b"short").is_none());
+}
+
+#[test]
+fn test_wrap_key() {
+    let wrap = SecretBox::generate();
+    let key = SecretBox::generate();
+    let contents = wrap_key(&wrap, &key);
+    assert_eq!(unwrap_key(&wrap, &contents).unwrap().bytes(), key.bytes());
+    assert!(unwrap_key(&SecretBox::generate(), &contents).is_none());
+    assert!(unwrap_key(&wrap, "not base58!").is_none());
+}
+
+#[async_std::test]
+async fn test_load_key() {
+    let dir = std::env::temp_dir();
+    let vault = dir.join(format!("vault-session-test-{}.sqlite3", std::process::id()));
+    let file = dir.join(format!("vault-session-test-{}.key", std::process::id()));
+    let _ = std::fs::remove_file(&vault);
+    let _ = std::fs::remove_file(&file);
+    let db = db::create_db(&vault).await.unwrap();
+
+    let key = load_key(&db, &file).await.unwrap();
+    assert_eq!(load_key(&db, &file).await.unwrap().bytes(), key.bytes());
+
+    // Without the wrap key, the file is useless, so there's a new one:
+    db.delete_setting(db::SETTING_SESSION_WRAP_KEY).await.unwrap();
+    let new_key = load_key(&db, &file).await.unwrap();
+    assert_ne!(new_key.bytes(), key.bytes());
+    assert_eq!(load_key(&db, &file).await.unwrap().bytes(), new_key.bytes());
+
+    db.close().await;
+    let _ = std::fs::remove_file(&vault);
+    let _ = std::fs::remove_file(&file);
+}
@@ -0,0 +1,250 @@
+use std::{sync::Arc, time::Duration};
+
+use async_std::sync::Mutex;
+use comrak::ComrakOptions;
+use tera_embed::TeraEmbed;
+use tide::http::{Method, Request, Response, StatusCode, Url};
+
+use crate::{crypto::{SealedBoxPrivateKey, SecretBox}, db::{self, VaultExt as _}};
+use super::{Access, AppRequest, AppState, CsrfToken, WritePost, read_write_form, session};
+
+/// No write password, and anyone can shut down.
+const OPEN: Access = Access{ write_password: None, shutdown_needs_login: false };
+
+/// The whole app, on an empty vault, plus a /form route that echoes what read_write_form() gets.
+async fn test_app(access: Access) -> (tide::Server<AppState>, SealedBoxPrivateKey) {
+    let key = SealedBoxPrivateKey::generate();
+    let db = db::create_memory_db().await.unwrap();
+    db.write_setting(db::SETTING_PUBLIC_KEY, &key.public().to_string()).await.unwrap();
+    let state = AppState {
+        templates: TeraEmbed::new(),
+        stopper: Arc::new(Mutex::new(stop_token::StopSource::new())),
+        nav: Vec::new(),
+        markdown_opts: ComrakOptions::default(),
+        db,
+        secret_box: SecretBox::generate(),
+        tls: false,
+        access,
+        session_limits: session::Limits{ lifetime: Duration::from_secs(3600), idle: Duration::from_secs(600) },
+        login_throttle: Default::default(),
+        public_key: key.public().clone(),
+    };
+    let mut app = super::app(state, false);
+    app.at("/form")
+        .get(|req: AppRequest| async move {
+            Ok(req.ext::<CsrfToken>().unwrap().0.clone())
+        })
+        .post(|mut req: AppRequest| async move {
+            let (WritePost{post, ..}, uploads) = read_write_form(&mut req).await?;
+            let files: Vec<String> = uploads.iter()
+                .map(|u| format!("{}={}", u.name, String::from_utf8(u.key.decrypt(&u.contents).unwrap()).unwrap()))
+                .collect();
+            Ok(format!("{} {}", post, files.join(",")))
+        });
+    (app, key)
+}
+
+/// A CSRF token, and the cookie it goes with.
+async fn new_session(app: &tide::Server<AppState>) -> (String, String) {
+    let req = Request::new(Method::Get, Url::parse("http://localhost/form").unwrap());
+    let mut res: Response = app.respond(req).await.unwrap();
+    let cookie = res.header("Set-Cookie").unwrap().last().as_str().split(';').next().unwrap().to_string();
+    (res.body_string().await.unwrap(), cookie)
+}
+
+/// A request to the API, with an optional bearer token and JSON body.
+fn api(method: Method, path: &str, token: Option<&str>, json: Option<&str>) -> Request {
+    let mut req = Request::new(method, Url::parse("http://localhost").unwrap().join(path).unwrap());
+    if let Some(token) = token {
+        req.insert_header("Authorization", format!("Bearer {}", token));
+    }
+    if let Some(json) = json {
+        req.insert_header("Content-Type", "application/json");
+        req.set_body(json);
+    }
+    req
+}
+
+fn post(url: &str, cookie: &str) -> Request {
+    let mut req = Request::new(Method::Post, Url::parse(url).unwrap());
+    req.insert_header("Cookie", cookie);
+    req.insert_header("Content-Type", "application/x-www-form-urlencoded");
+    req.set_body("post=hello");
+    req
+}
+
+#[async_std::test]
+async fn test_csrf() {
+    let (app, _) = test_app(OPEN).await;
+    let (token, cookie) = new_session(&app).await;
+
+    let mut res: Response = app.respond(post(&format!("http://localhost/form?csrf={}", token), &cookie)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+    assert_eq!(res.body_string().await.unwrap(), "hello ");
+
+    let mut req = post("http://localhost/form", &cookie);
+    req.insert_header("X-CSRF-Token", token.as_str());
+    let res: Response = app.respond(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+
+    // Missing, wrong, from another session, or without a session:
+    let (other_token, _) = new_session(&app).await;
+    for url in ["http://localhost/form", "http://localhost/form?csrf=wrong", &format!("http://localhost/form?csrf={}", other_token)] {
+        let res: Response = app.respond(post(url, &cookie)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden, "{}", url);
+    }
+    let res: Response = app.respond(post(&format!("http://localhost/form?csrf={}", token), "")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Forbidden);
+
+    // In the body doesn't count, since that's not read:
+    let mut req = post("http://localhost/form", &cookie);
+    req.set_body(format!("csrf={}&post=hello", token));
+    let res: Response = app.respond(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Forbidden);
+
+    // The API uses bearer tokens and JSON instead:
+    let res: Response = app.respond(api(Method::Post, "/api/v1/entries", None, Some(r#"{"contents": "hi"}"#))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Created);
+}
+
+#[async_std::test]
+async fn test_csrf_multipart() {
+    let (app, _) = test_app(OPEN).await;
+    let (token, cookie) = new_session(&app).await;
+
+    let mut req = post(&format!("http://localhost/form?csrf={}", token), &cookie);
+    req.insert_header("Content-Type", "multipart/form-data; boundary=XYZ");
+    req.set_body(concat!(
+        "--XYZ\r\nContent-Disposition: form-data; name=\"post\"\r\n\r\nhello\r\n",
+        "--XYZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n",
+        "Content-Type: text/plain\r\n\r\nfile contents\r\n",
+        "--XYZ--\r\n",
+    ));
+    let mut res: Response = app.respond(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+    assert_eq!(res.body_string().await.unwrap(), "hello a.txt=file contents");
+}
+
+#[async_std::test]
+async fn test_api_read() {
+    let (app, key) = test_app(OPEN).await;
+    let db = &app.state().db;
+    let mut res: Response = app.respond(api(Method::Post, "/api/v1/entries", None, Some(r#"{"contents": "first", "tags": ["work"]}"#))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Created);
+    let written: serde_json::Value = res.body_json().await.unwrap();
+    let first = written["timestamp_ms_utc"].as_i64().unwrap();
+
+    // Reading needs the API token:
+    for token in [None, Some("wrong")] {
+        let res: Response = app.respond(api(Method::Get, "/api/v1/entries", token, None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        assert_eq!(res.header("WWW-Authenticate").unwrap().last().as_str(), "Bearer");
+    }
+    let token = db.enable_api_token(&key).await.unwrap();
+
+    let mut res: Response = app.respond(api(Method::Get, "/api/v1/entries", Some(&token), None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+    let listed: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(listed["entries"][0]["timestamp_ms_utc"], first);
+    assert_eq!(listed["entries"][0]["contents"], "first");
+    assert_eq!(listed["entries"][0]["tags"], serde_json::json!(["work"]));
+
+    let mut res: Response = app.respond(api(Method::Get, &format!("/api/v1/entries/{}", first), Some(&token), None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+    let entry: serde_json::Value = res.body_json().await.unwrap();
+    assert_eq!(entry["contents"], "first");
+    let res: Response = app.respond(api(Method::Get, "/api/v1/entries/12345", Some(&token), None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NotFound);
+
+    // Revoked:
+    db.delete_setting(db::SETTING_API_KEY).await.unwrap();
+    for path in ["/api/v1/entries".to_string(), format!("/api/v1/entries/{}", first)] {
+        let res: Response = app.respond(api(Method::Get, &path, Some(&token), None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized, "{}", path);
+    }
+}
+
+#[async_std::test]
+async fn test_api_write() {
+    let access = Access{
+        write_password: Some(crate::crypto::hash_password("write password").unwrap()),
+        shutdown_needs_login: false,
+    };
+    let (app, key) = test_app(access).await;
+    let db = &app.state().db;
+    let token = db.enable_api_token(&key).await.unwrap();
+    let body = Some(r#"{"contents": "hello"}"#);
+
+    for wrong in [None, Some("wrong password")] {
+        let res: Response = app.respond(api(Method::Post, "/api/v1/entries", wrong, body)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+    assert!(db.all_entries().await.unwrap().is_empty());
+
+    for allowed in ["write password", token.as_str()] {
+        let res: Response = app.respond(api(Method::Post, "/api/v1/entries", Some(allowed), body)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Created);
+    }
+    assert_eq!(db.all_entries().await.unwrap().len(), 2);
+
+    // A form post from another website can't be JSON, which is why there's no CSRF token:
+    let mut req = api(Method::Post, "/api/v1/entries", Some(&token), None);
+    req.insert_header("Content-Type", "application/x-www-form-urlencoded");
+    req.set_body("contents=hello");
+    let res: Response = app.respond(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UnsupportedMediaType);
+    let res: Response = app.respond(api(Method::Post, "/api/v1/entries", Some(&token), Some(r#"{"contents": " "}"#))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BadRequest);
+    assert_eq!(db.all_entries().await.unwrap().len(), 2);
+}
+
+#[async_std::test]
+async fn test_access() {
+    let access = Access{
+        write_password: Some(crate::crypto::hash_password("write password").unwrap()),
+        shutdown_needs_login: true,
+    };
+    let (app, _) = test_app(access).await;
+    let (token, csrf_cookie) = new_session(&app).await;
+    let write = |cookie: &str| {
+        let mut req = post(&format!("http://localhost/?csrf={}", token), cookie);
+        req.set_body("post=hello&submit=Submit");
+        req
+    };
+    let shutdown = |cookie: &str| post(&format!("http://localhost/shutdown?csrf={}", token), cookie);
+
+    // Without the write password, both go to the login page:
+    for req in [write(&csrf_cookie), shutdown(&csrf_cookie)] {
+        let res: Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SeeOther);
+        assert_eq!(res.header("Location").unwrap().last().as_str(), "/login");
+    }
+    assert!(app.state().db.all_entries().await.unwrap().is_empty());
+
+    let mut login = post(&format!("http://localhost/login?csrf={}", token), &csrf_cookie);
+    login.set_body("secret=write%20password");
+    let res: Response = app.respond(login).await.unwrap();
+    assert_eq!(res.header("Location").unwrap().last().as_str(), "/");
+    let write_cookie = res.header("Set-Cookie").unwrap().iter()
+        .map(|value| value.as_str().split(';').next().unwrap())
+        .find(|cookie| cookie.starts_with("write="))
+        .unwrap();
+    let cookie = format!("{}; {}", csrf_cookie, write_cookie);
+
+    let res: Response = app.respond(write(&cookie)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+    assert_eq!(app.state().db.all_entries().await.unwrap().len(), 1);
+    let res: Response = app.respond(shutdown(&cookie)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+}
+
+#[async_std::test]
+async fn test_open_access() {
+    let (app, _) = test_app(OPEN).await;
+    let (token, cookie) = new_session(&app).await;
+    let mut req = post(&format!("http://localhost/?csrf={}", token), &cookie);
+    req.set_body("post=hello&submit=Submit");
+    let res: Response = app.respond(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+    assert_eq!(app.state().db.all_entries().await.unwrap().len(), 1);
+}
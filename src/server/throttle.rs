@@ -0,0 +1,135 @@
+//! Slows down password guessing. After a few failed logins from an address, each failure doubles
+//! how long it has to wait to try again. There's also a cap on the rate of attempts from all
+//! addresses together, so spreading a guessing attack across many addresses doesn't get around
+//! that. The cap only ever means a short wait, so other clients can't lock the owner out.
+//!
+//! Loopback and Unix socket peers may be a reverse proxy, with every client behind it sharing its
+//! address. Backing off that address would let anyone lock out the owner, so only the cap applies.
+
+#[cfg(test)]
+mod tests;
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+struct Rule {
+    /// Failures allowed before any waiting.
+    free: u32,
+    /// The wait after the first failure past `free`. Doubles from there.
+    base: Duration,
+    max: Duration,
+}
+
+const PER_ADDRESS: Rule = Rule { free: 3, base: Duration::from_secs(1), max: Duration::from_secs(15 * 60) };
+
+struct RateCap {
+    /// Attempts allowed at once, before any waiting.
+    burst: u32,
+    /// The time each attempt takes to be forgotten.
+    interval: Duration,
+}
+
+const GLOBAL: RateCap = RateCap { burst: 10, interval: Duration::from_secs(6) };
+
+/// Failures this old start over.
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Past this many addresses, forget the ones that aren't waiting.
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Default)]
+pub(super) struct LoginThrottle {
+    state: Mutex<State>,
+}
+
+/// Addresses are None for Unix sockets.
+#[derive(Default)]
+struct State {
+    by_address: HashMap<Option<IpAddr>, Failures>,
+    global: Attempts,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Option<Instant>,
+}
+
+impl Failures {
+    fn wait(&self, rule: &Rule, now: Instant) -> Duration {
+        let last = match self.last {
+            Some(last) if self.count >= rule.free => last,
+            _ => return Duration::from_secs(0),
+        };
+        let doublings = (self.count - rule.free).min(20);
+        let delay = (rule.base * (1 << doublings)).min(rule.max);
+        (last + delay).saturating_duration_since(now)
+    }
+
+    fn fail(&mut self, now: Instant) {
+        if self.last.map(|last| now.saturating_duration_since(last) > FORGET_AFTER).unwrap_or(false) {
+            self.count = 0;
+        }
+        self.count = self.count.saturating_add(1);
+        self.last = Some(now);
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Attempts {
+    /// When every attempt so far will have been forgotten.
+    clear_at: Option<Instant>,
+}
+
+impl Attempts {
+    fn wait(&self, cap: &RateCap, now: Instant) -> Duration {
+        let pending = self.clear_at.map(|at| at.saturating_duration_since(now)).unwrap_or_default();
+        pending.saturating_sub(cap.interval * (cap.burst - 1))
+    }
+
+    fn attempt(&mut self, cap: &RateCap, now: Instant) {
+        self.clear_at = Some(self.clear_at.unwrap_or(now).max(now) + cap.interval);
+    }
+}
+
+/// Maybe a reverse proxy, standing in for many clients. See above.
+fn is_shared(address: Option<IpAddr>) -> bool {
+    address.map(|ip| ip.is_loopback()).unwrap_or(true)
+}
+
+impl LoginThrottle {
+    /// Call before checking a password. Returns how long `address` must wait first, if at all.
+    /// Otherwise, the attempt counts as a failure until `succeeded`. Checking and counting at
+    /// once means concurrent requests can't all get in before the first failure is counted.
+    pub(super) fn attempt(&self, address: Option<IpAddr>, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().expect("login throttle lock");
+        let shared = is_shared(address);
+        let mut wait = state.global.wait(&GLOBAL, now);
+        if !shared {
+            if let Some(failures) = state.by_address.get(&address) {
+                wait = wait.max(failures.wait(&PER_ADDRESS, now));
+            }
+        }
+        if wait > Duration::from_secs(0) {
+            return Some(wait);
+        }
+
+        state.global.attempt(&GLOBAL, now);
+        if !shared {
+            state.by_address.entry(address).or_default().fail(now);
+            if state.by_address.len() > MAX_TRACKED {
+                state.by_address.retain(|_, failures| failures.wait(&PER_ADDRESS, now) > Duration::from_secs(0));
+            }
+        }
+        None
+    }
+
+    pub(super) fn succeeded(&self, address: Option<IpAddr>) {
+        let mut state = self.state.lock().expect("login throttle lock");
+        state.by_address.remove(&address);
+    }
+}
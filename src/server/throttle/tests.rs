@@ -0,0 +1,70 @@
+use std::{net::IpAddr, time::{Duration, Instant}};
+
+use super::LoginThrottle;
+
+#[test]
+fn test_backoff() {
+    let throttle = LoginThrottle::default();
+    let attacker: Option<IpAddr> = Some("192.0.2.1".parse().unwrap());
+    let owner: Option<IpAddr> = Some("192.0.2.2".parse().unwrap());
+    let start = Instant::now();
+    let secs = |s: u64| start + Duration::from_secs(s);
+
+    // Each attempt counts as a failure up front, so these could all be in flight at once:
+    for _ in 0..3 {
+        assert_eq!(throttle.attempt(attacker, start), None);
+    }
+    // Past the free attempts, each failure doubles the wait:
+    assert_eq!(throttle.attempt(attacker, start), Some(Duration::from_secs(1)));
+    assert_eq!(throttle.attempt(attacker, secs(1)), None);
+    assert_eq!(throttle.attempt(attacker, secs(1)), Some(Duration::from_secs(2)));
+    assert_eq!(throttle.attempt(attacker, secs(3)), None);
+    assert_eq!(throttle.attempt(owner, secs(3)), None);
+
+    throttle.succeeded(attacker);
+    assert_eq!(throttle.attempt(attacker, secs(3)), None);
+}
+
+#[test]
+fn test_success_resets() {
+    let throttle = LoginThrottle::default();
+    let owner: Option<IpAddr> = Some("192.0.2.2".parse().unwrap());
+    let start = Instant::now();
+    for _ in 0..10 {
+        assert_eq!(throttle.attempt(owner, start), None);
+        throttle.succeeded(owner);
+    }
+}
+
+#[test]
+fn test_global_rate_cap() {
+    let throttle = LoginThrottle::default();
+    let start = Instant::now();
+    for i in 0..10u8 {
+        assert_eq!(throttle.attempt(Some(IpAddr::from([192, 0, 2, i])), start), None);
+    }
+    // Every address waits, even one that never failed. But not for long:
+    let fresh = Some(IpAddr::from([198, 51, 100, 1]));
+    assert_eq!(throttle.attempt(fresh, start), Some(Duration::from_secs(6)));
+    assert_eq!(throttle.attempt(fresh, start + Duration::from_secs(6)), None);
+    assert_eq!(throttle.attempt(fresh, start + Duration::from_secs(6)), Some(Duration::from_secs(6)));
+}
+
+#[test]
+fn test_behind_proxy() {
+    // Through a proxy on a Unix socket or loopback, the attacker and owner have the same address.
+    for proxy in [None, Some("127.0.0.1".parse().unwrap()), Some("::1".parse().unwrap())] {
+        let throttle = LoginThrottle::default();
+        let start = Instant::now();
+        let mut now = start;
+        for _ in 0..1000 {
+            if let Some(wait) = throttle.attempt(proxy, now) {
+                now += wait;
+            }
+        }
+        // However long the attacker keeps going, the owner never waits longer than the cap:
+        let wait = throttle.attempt(proxy, now).unwrap_or_default();
+        assert!(wait <= Duration::from_secs(6), "{:?}", wait);
+        assert_eq!(throttle.attempt(proxy, now + wait), None);
+    }
+}
@@ -17,13 +17,18 @@ pub(crate) async fn serve<RE: RustEmbed, T>(req: tide::Request<T>) -> tide::Resu
         // This is likely doing a lot of extra copying. Would be nice if Tide took a Cow<bytes>
         .body(file.data.as_ref());
 
-    if let Some(guess) =  mime_guess::from_path(path).first() {
-        let mut ctype = guess.to_string();
-        if ctype.starts_with("text/") {
-            ctype.push_str("; charset=utf-8");
-        }
+    if let Some(ctype) = content_type(path) {
         response = response.header("Content-Type", ctype);
     }
 
     Ok(response .build())
+}
+
+/// Guess a Content-Type header from a file name.
+pub(crate) fn content_type(path: &str) -> Option<String> {
+    let mut ctype = mime_guess::from_path(path).first()?.to_string();
+    if ctype.starts_with("text/") {
+        ctype.push_str("; charset=utf-8");
+    }
+    Some(ctype)
 }
\ No newline at end of file
@@ -0,0 +1,54 @@
+//! Tags for entries, like `#work`.
+//!
+//! Tag names are sealed to the vault's public key like entry contents. To filter by tag without
+//! decrypting everything, each tag also gets a hash keyed with the private key. Entries can be
+//! written without logging in, so that hash is filled in later by VaultExt::index_tags().
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::{BTreeSet, HashMap};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::{crypto::SealedBoxPrivateKey, db::VaultExt};
+
+/// The `context` for SealedBoxPrivateKey::keyed_hash() of tag names.
+pub(crate) const HASH_CONTEXT: &str = "vault.tag";
+
+// Must follow whitespace, so that "# Heading" and "page#anchor" aren't tags.
+static HASHTAG: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:^|\s)#([\p{L}\p{N}_][\p{L}\p{N}_-]*)").expect("Valid regex")
+});
+
+/// `#hashtags` in the text of an entry.
+pub(crate) fn parse_hashtags(text: &str) -> BTreeSet<String> {
+    HASHTAG.captures_iter(text)
+        .map(|c| normalize(&c[1]))
+        .collect()
+}
+
+/// Tags typed into a tag field, separated by commas or spaces. The leading `#` is optional.
+pub(crate) fn parse_tag_field(field: &str) -> BTreeSet<String> {
+    field.split(|c: char| c == ',' || c.is_whitespace())
+        .map(|t| normalize(t.trim_start_matches('#')))
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Sorted tag names for each entry in a time range, by timestamp_ms_utc.
+pub(crate) async fn decrypt_tags(db: &sqlx::SqlitePool, key: &SealedBoxPrivateKey, from_ms_utc: i64, to_ms_utc: i64) -> anyhow::Result<HashMap<i64, Vec<String>>> {
+    let mut names: HashMap<i64, BTreeSet<String>> = HashMap::new();
+    for tag in db.tags_between(from_ms_utc, to_ms_utc).await? {
+        names.entry(tag.entry_timestamp_ms_utc)
+            .or_default()
+            .insert(key.decrypt_string(&tag.tag)?);
+    }
+    Ok(names.into_iter().map(|(ts, names)| (ts, names.into_iter().collect())).collect())
+}
+
+/// Tags match case-insensitively.
+pub(crate) fn normalize(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
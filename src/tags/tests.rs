@@ -0,0 +1,15 @@
+use super::{parse_hashtags, parse_tag_field};
+
+#[test]
+fn test_parse_hashtags() {
+    let tags = parse_hashtags("# Heading\n#Work was fine. #home-life, see page#anchor and #日記\n##nope");
+    let tags: Vec<&str> = tags.iter().map(|t| t.as_str()).collect();
+    assert_eq!(tags, vec!["home-life", "work", "日記"]);
+}
+
+#[test]
+fn test_parse_tag_field() {
+    let tags = parse_tag_field(" #Work, travel  Work,,");
+    let tags: Vec<&str> = tags.iter().map(|t| t.as_str()).collect();
+    assert_eq!(tags, vec!["travel", "work"]);
+}
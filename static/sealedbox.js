@@ -0,0 +1,482 @@
+// Sealed boxes (libsodium's crypto_box_seal) in plain JavaScript, for `vault serve --client-side`.
+// The browser encrypts and decrypts entries itself, so the server never sees plaintext or the private key.
+//
+// Only what sealed boxes need: X25519 (RFC 7748), XSalsa20-Poly1305, and BLAKE2b (RFC 7693) for
+// the nonce. X25519 and XSalsa20-Poly1305 follow TweetNaCl, whose field arithmetic is constant-time:
+// no branches or table lookups on secret data. BLAKE2b follows blakejs, and only hashes public keys.
+// src/crypto/tests.rs checks that libsodium can open what this seals.
+//
+// TODO: Replace the hand-ported primitives with unmodified copies of tweetnacl-js (nacl-fast.js)
+// and blakejs, with their license headers, and keep only the sealed box construction here.
+"use strict"
+
+const sealedBox = (() => {
+    const PUBLIC_KEY_BYTES = 32
+    const MAC_BYTES = 16
+    const SEAL_BYTES = PUBLIC_KEY_BYTES + MAC_BYTES
+
+    const concat = (...arrays) => {
+        const out = new Uint8Array(arrays.reduce((sum, a) => sum + a.length, 0))
+        let offset = 0
+        for (const a of arrays) {
+            out.set(a, offset)
+            offset += a.length
+        }
+        return out
+    }
+
+    // --- X25519 ---
+
+    // Field elements mod 2^255 - 19, as 16 limbs of 16 bits. Limbs can briefly overflow 16 bits
+    // or go negative, so they're floats; carry() brings them back.
+    const gf = (init) => {
+        const r = new Float64Array(16)
+        if (init) { r.set(init) }
+        return r
+    }
+    const A24 = gf([0xdb41, 1])
+
+    function carry(o) {
+        let c = 1
+        for (let i = 0; i < 16; i++) {
+            const v = o[i] + c + 65535
+            c = Math.floor(v / 65536)
+            o[i] = v - c * 65536
+        }
+        o[0] += c - 1 + 37 * (c - 1)
+    }
+
+    // Swap p and q if b is 1, without branching on it.
+    function select(p, q, b) {
+        const mask = ~(b - 1)
+        for (let i = 0; i < 16; i++) {
+            const t = mask & (p[i] ^ q[i])
+            p[i] ^= t
+            q[i] ^= t
+        }
+    }
+
+    function pack(n) {
+        const m = gf()
+        const t = gf(n)
+        carry(t)
+        carry(t)
+        carry(t)
+        for (let j = 0; j < 2; j++) {
+            m[0] = t[0] - 0xffed
+            for (let i = 1; i < 15; i++) {
+                m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1)
+                m[i - 1] &= 0xffff
+            }
+            m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1)
+            const b = (m[15] >> 16) & 1
+            m[14] &= 0xffff
+            select(t, m, 1 - b)
+        }
+        const out = new Uint8Array(32)
+        for (let i = 0; i < 16; i++) {
+            out[2 * i] = t[i] & 0xff
+            out[2 * i + 1] = t[i] >> 8
+        }
+        return out
+    }
+
+    function unpack(bytes) {
+        const o = gf()
+        for (let i = 0; i < 16; i++) { o[i] = bytes[2 * i] + (bytes[2 * i + 1] << 8) }
+        o[15] &= 0x7fff
+        return o
+    }
+
+    const add = (o, a, b) => { for (let i = 0; i < 16; i++) { o[i] = a[i] + b[i] } }
+    const sub = (o, a, b) => { for (let i = 0; i < 16; i++) { o[i] = a[i] - b[i] } }
+    function mul(o, a, b) {
+        const t = new Float64Array(31)
+        for (let i = 0; i < 16; i++) {
+            for (let j = 0; j < 16; j++) { t[i + j] += a[i] * b[j] }
+        }
+        for (let i = 0; i < 15; i++) { t[i] += 38 * t[i + 16] }
+        for (let i = 0; i < 16; i++) { o[i] = t[i] }
+        carry(o)
+        carry(o)
+    }
+    const square = (o, a) => mul(o, a, a)
+
+    // a^(p - 2). The exponent is public, so branching on its bits is fine.
+    function inverse(a) {
+        const c = gf(a)
+        for (let i = 253; i >= 0; i--) {
+            square(c, c)
+            if (i !== 2 && i !== 4) { mul(c, c, a) }
+        }
+        return c
+    }
+
+    function x25519(scalar, point) {
+        const k = Uint8Array.from(scalar)
+        k[0] &= 248
+        k[31] &= 127
+        k[31] |= 64
+        const x = unpack(point)
+        const [a, b, c, d, e, f] = [gf([1]), gf(x), gf(), gf([1]), gf(), gf()]
+
+        // The Montgomery ladder:
+        for (let i = 254; i >= 0; i--) {
+            const bit = (k[i >>> 3] >>> (i & 7)) & 1
+            select(a, b, bit)
+            select(c, d, bit)
+            add(e, a, c)
+            sub(a, a, c)
+            add(c, b, d)
+            sub(b, b, d)
+            square(d, e)
+            square(f, a)
+            mul(a, c, a)
+            mul(c, b, e)
+            add(e, a, c)
+            sub(a, a, c)
+            square(b, a)
+            sub(c, d, f)
+            mul(a, c, A24)
+            add(a, a, d)
+            mul(c, c, a)
+            mul(a, d, f)
+            mul(d, b, x)
+            square(b, e)
+            select(a, b, bit)
+            select(c, d, bit)
+        }
+
+        mul(a, a, inverse(c))
+        const shared = pack(a)
+        if (shared.every((byte) => byte === 0)) {
+            throw new Error("Invalid public key")
+        }
+        return shared
+    }
+
+    const BASE_POINT = Uint8Array.from([9, ...new Array(31).fill(0)])
+    const publicKey = (privateKey) => x25519(privateKey, BASE_POINT)
+
+    // --- Salsa20 ---
+
+    const SIGMA = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]
+    const rotl = (x, n) => (x << n) | (x >>> (32 - n))
+    const words = (bytes) => {
+        const view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength)
+        return Array.from({length: bytes.length / 4}, (_, i) => view.getUint32(i * 4, true))
+    }
+
+    // 20 rounds over a key and 16 bytes of nonce and counter.
+    // HSalsa20 keeps some of the mixed state; Salsa20 adds the input back in.
+    function salsa(key, input, hsalsa) {
+        const k = words(key)
+        const n = words(input)
+        const s = Uint32Array.from([
+            SIGMA[0], k[0], k[1], k[2],
+            k[3], SIGMA[1], n[0], n[1],
+            n[2], n[3], SIGMA[2], k[4],
+            k[5], k[6], k[7], SIGMA[3],
+        ])
+        const x = Uint32Array.from(s)
+        const quarter = (a, b, c, d) => {
+            x[b] ^= rotl(x[a] + x[d], 7)
+            x[c] ^= rotl(x[b] + x[a], 9)
+            x[d] ^= rotl(x[c] + x[b], 13)
+            x[a] ^= rotl(x[d] + x[c], 18)
+        }
+        for (let i = 0; i < 10; i++) {
+            quarter(0, 4, 8, 12)
+            quarter(5, 9, 13, 1)
+            quarter(10, 14, 2, 6)
+            quarter(15, 3, 7, 11)
+            quarter(0, 1, 2, 3)
+            quarter(5, 6, 7, 4)
+            quarter(10, 11, 8, 9)
+            quarter(15, 12, 13, 14)
+        }
+
+        const out = hsalsa
+            ? Uint32Array.from([0, 5, 10, 15, 6, 7, 8, 9], (i) => x[i])
+            : x.map((word, i) => word + s[i])
+        const bytes = new Uint8Array(out.length * 4)
+        const view = new DataView(bytes.buffer)
+        out.forEach((word, i) => view.setUint32(i * 4, word, true))
+        return bytes
+    }
+
+    function xsalsa20Stream(key, nonce, length) {
+        const subkey = salsa(key, nonce.subarray(0, 16), true)
+        const out = new Uint8Array(length)
+        const input = new Uint8Array(16)
+        input.set(nonce.subarray(16, 24))
+        for (let block = 0; block * 64 < length; block++) {
+            new DataView(input.buffer).setUint32(8, block, true)
+            out.set(salsa(subkey, input, false).subarray(0, Math.min(64, length - block * 64)), block * 64)
+        }
+        return out
+    }
+
+    // --- Poly1305 ---
+
+    const MINUS_P = [5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 252]
+    const add1305 = (h, c) => {
+        let u = 0
+        for (let j = 0; j < 17; j++) {
+            u += h[j] + c[j]
+            h[j] = u & 255
+            u >>>= 8
+        }
+    }
+
+    function poly1305(message, key) {
+        const r = new Array(17).fill(0)
+        const h = new Array(17).fill(0)
+        for (let j = 0; j < 16; j++) { r[j] = key[j] }
+        r[3] &= 15; r[4] &= 252; r[7] &= 15; r[8] &= 252
+        r[11] &= 15; r[12] &= 252; r[15] &= 15
+
+        for (let pos = 0; pos < message.length; pos += 16) {
+            const c = new Array(17).fill(0)
+            const chunk = message.subarray(pos, pos + 16)
+            chunk.forEach((b, j) => { c[j] = b })
+            c[chunk.length] = 1
+            add1305(h, c)
+
+            const x = new Array(17).fill(0)
+            for (let i = 0; i < 17; i++) {
+                for (let j = 0; j < 17; j++) {
+                    x[i] += h[j] * (j <= i ? r[i - j] : 320 * r[i + 17 - j])
+                }
+            }
+            let u = 0
+            for (let j = 0; j < 16; j++) {
+                u += x[j]
+                h[j] = u & 255
+                u = Math.floor(u / 256)
+            }
+            u += x[16]
+            h[16] = u & 3
+            u = 5 * Math.floor(u / 4)
+            for (let j = 0; j < 16; j++) {
+                u += h[j]
+                h[j] = u & 255
+                u >>>= 8
+            }
+            h[16] += u
+        }
+
+        const g = h.slice()
+        add1305(h, MINUS_P)
+        const mask = -(h[16] >>> 7)
+        for (let j = 0; j < 17; j++) { h[j] ^= mask & (g[j] ^ h[j]) }
+        const s = Array.from(key.subarray(16, 32))
+        s.push(0)
+        add1305(h, s)
+        return Uint8Array.from(h.slice(0, 16))
+    }
+
+    // --- XSalsa20-Poly1305 (crypto_box_easy) ---
+
+    function boxKey(publicKey, privateKey) {
+        return salsa(x25519(privateKey, publicKey), new Uint8Array(16), true)
+    }
+
+    function secretboxSeal(message, nonce, key) {
+        const stream = xsalsa20Stream(key, nonce, 32 + message.length)
+        const cipher = message.map((b, i) => b ^ stream[32 + i])
+        return concat(poly1305(cipher, stream.subarray(0, 32)), cipher)
+    }
+
+    function secretboxOpen(boxed, nonce, key) {
+        const mac = boxed.subarray(0, MAC_BYTES)
+        const cipher = boxed.subarray(MAC_BYTES)
+        const stream = xsalsa20Stream(key, nonce, 32 + cipher.length)
+        const expected = poly1305(cipher, stream.subarray(0, 32))
+        if (expected.reduce((diff, b, i) => diff | (b ^ mac[i]), 0) !== 0) {
+            throw new Error("Couldn't decrypt")
+        }
+        return cipher.map((b, i) => b ^ stream[32 + i])
+    }
+
+    // --- BLAKE2b ---
+
+    // 64-bit words as pairs of 32-bit halves, low half first, so there's no BigInt.
+    const IV = Uint32Array.from([
+        0xf3bcc908, 0x6a09e667, 0x84caa73b, 0xbb67ae85, 0xfe94f82b, 0x3c6ef372, 0x5f1d36f1, 0xa54ff53a,
+        0xade682d1, 0x510e527f, 0x2b3e6c1f, 0x9b05688c, 0xfb41bd6b, 0x1f83d9ab, 0x137e2179, 0x5be0cd19,
+    ])
+    const PERMUTATIONS = [
+        [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+        [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+        [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+        [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+        [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+        [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+        [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+        [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+        [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+        [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+    ]
+
+    // Add (low, high) to the word at v[i]. Uint32Array wraps the high half.
+    const add64 = (v, i, low, high) => {
+        const sum = v[i] + low
+        v[i] = sum
+        v[i + 1] += high + Math.floor(sum / 0x100000000)
+    }
+    const xor64 = (v, i, j) => {
+        v[i] ^= v[j]
+        v[i + 1] ^= v[j + 1]
+    }
+    const rotr64 = (v, i, n) => {
+        let [low, high] = [v[i], v[i + 1]]
+        if (n >= 32) {
+            [low, high] = [high, low]
+            n -= 32
+        }
+        if (n > 0) {
+            [low, high] = [(low >>> n) | (high << (32 - n)), (high >>> n) | (low << (32 - n))]
+        }
+        v[i] = low
+        v[i + 1] = high
+    }
+
+    function compress(h, block, counter, last) {
+        const v = new Uint32Array(32)
+        v.set(h)
+        v.set(IV, 16)
+        v[24] ^= counter
+        v[25] ^= Math.floor(counter / 0x100000000)
+        if (last) {
+            v[28] = ~v[28]
+            v[29] = ~v[29]
+        }
+        const m = Uint32Array.from(words(block))
+        // Arguments are indexes of 64-bit words:
+        const mix = (a, b, c, d, x, y) => {
+            [a, b, c, d, x, y] = [a * 2, b * 2, c * 2, d * 2, x * 2, y * 2]
+            add64(v, a, v[b], v[b + 1])
+            add64(v, a, m[x], m[x + 1])
+            xor64(v, d, a)
+            rotr64(v, d, 32)
+            add64(v, c, v[d], v[d + 1])
+            xor64(v, b, c)
+            rotr64(v, b, 24)
+            add64(v, a, v[b], v[b + 1])
+            add64(v, a, m[y], m[y + 1])
+            xor64(v, d, a)
+            rotr64(v, d, 16)
+            add64(v, c, v[d], v[d + 1])
+            xor64(v, b, c)
+            rotr64(v, b, 63)
+        }
+        for (let round = 0; round < 12; round++) {
+            const s = PERMUTATIONS[round % 10]
+            mix(0, 4, 8, 12, s[0], s[1])
+            mix(1, 5, 9, 13, s[2], s[3])
+            mix(2, 6, 10, 14, s[4], s[5])
+            mix(3, 7, 11, 15, s[6], s[7])
+            mix(0, 5, 10, 15, s[8], s[9])
+            mix(1, 6, 11, 12, s[10], s[11])
+            mix(2, 7, 8, 13, s[12], s[13])
+            mix(3, 4, 9, 14, s[14], s[15])
+        }
+        for (let i = 0; i < 16; i++) { h[i] ^= v[i] ^ v[i + 16] }
+    }
+
+    function blake2b(data, length) {
+        const h = Uint32Array.from(IV)
+        h[0] ^= 0x01010000 ^ length
+        let offset = 0
+        while (data.length - offset > 128) {
+            offset += 128
+            compress(h, data.subarray(offset - 128, offset), offset, false)
+        }
+        const block = new Uint8Array(128)
+        block.set(data.subarray(offset))
+        compress(h, block, data.length, true)
+
+        const out = new Uint8Array(64)
+        const view = new DataView(out.buffer)
+        h.forEach((word, i) => view.setUint32(i * 4, word, true))
+        return out.subarray(0, length)
+    }
+
+    // --- Sealed boxes ---
+
+    const sealNonce = (ephemeralPublic, recipientPublic) => blake2b(concat(ephemeralPublic, recipientPublic), 24)
+
+    function seal(message, recipientPublic) {
+        const ephemeralPrivate = crypto.getRandomValues(new Uint8Array(32))
+        const ephemeralPublic = publicKey(ephemeralPrivate)
+        const nonce = sealNonce(ephemeralPublic, recipientPublic)
+        return concat(ephemeralPublic, secretboxSeal(message, nonce, boxKey(recipientPublic, ephemeralPrivate)))
+    }
+
+    // Opening a page of entries uses the same private key for each one:
+    const publicKeys = new WeakMap()
+
+    function open(sealed, privateKey) {
+        if (sealed.length < SEAL_BYTES) {
+            throw new Error("Couldn't decrypt")
+        }
+        if (!publicKeys.has(privateKey)) {
+            publicKeys.set(privateKey, publicKey(privateKey))
+        }
+        const ephemeralPublic = sealed.subarray(0, PUBLIC_KEY_BYTES)
+        const nonce = sealNonce(ephemeralPublic, publicKeys.get(privateKey))
+        return secretboxOpen(sealed.subarray(PUBLIC_KEY_BYTES), nonce, boxKey(ephemeralPublic, privateKey))
+    }
+
+    // --- Encodings ---
+
+    const BASE58 = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz"
+    // Like base 10, one byte at a time, with the number as little-endian base 58 (or 256) digits.
+    const convert = (digits, from, to) => {
+        const out = []
+        for (const digit of digits) {
+            let carried = digit
+            for (let i = 0; i < out.length; i++) {
+                carried += out[i] * from
+                out[i] = carried % to
+                carried = Math.floor(carried / to)
+            }
+            for (; carried > 0; carried = Math.floor(carried / to)) { out.push(carried % to) }
+        }
+        return out.reverse()
+    }
+    const base58 = {
+        // Leading zero bytes are written as leading "1"s.
+        encode(bytes) {
+            const zeros = bytes.findIndex((b) => b !== 0)
+            const leading = zeros < 0 ? bytes.length : zeros
+            return "1".repeat(leading) + convert(bytes, 256, 58).map((d) => BASE58[d]).join("")
+        },
+        decode(text) {
+            const digits = Array.from(text, (char) => {
+                const digit = BASE58.indexOf(char)
+                if (digit < 0) { throw new Error("Not base58") }
+                return digit
+            })
+            const zeros = digits.findIndex((d) => d !== 0)
+            const leading = zeros < 0 ? digits.length : zeros
+            return Uint8Array.from([...new Array(leading).fill(0), ...convert(digits, 58, 256)])
+        },
+    }
+
+    const base64 = {
+        encode: (bytes) => btoa(Array.from(bytes, (b) => String.fromCharCode(b)).join("")),
+        decode: (text) => Uint8Array.from(atob(text), (c) => c.charCodeAt(0)),
+    }
+
+    return {
+        publicKey,
+        seal,
+        open,
+        base58,
+        base64,
+        sealString: (text, recipientPublic) => base64.encode(seal(new TextEncoder().encode(text), recipientPublic)),
+        openString: (sealed, privateKey) => new TextDecoder("utf-8", {fatal: true}).decode(open(base64.decode(sealed), privateKey)),
+    }
+})()